@@ -0,0 +1,287 @@
+//! Atom 1.0 feed support.
+
+use crate::storage::{StoredFeed, item_pub_date, item_to_guid};
+use anyhow::Context;
+use atom_syndication::{Content, Entry, Feed, Link, Person, Text};
+use rss::{Category, Channel, Enclosure, Guid, Item, extension::dublincore::DublinCoreExtension};
+
+/// The MIME type for Atom documents.
+pub const MIME_TYPE: &str = "application/atom+xml";
+
+/// Parses an Atom document and normalizes it into an RSS channel.
+pub fn read_channel(content: &[u8]) -> anyhow::Result<Channel> {
+    let feed = Feed::read_from(content).context("Failed to parse Atom feed")?;
+
+    let channel = Channel {
+        title: feed.title().as_str().to_owned(),
+        link: alternate_link(feed.links()).unwrap_or_default(),
+        description: feed
+            .subtitle()
+            .map(|subtitle| subtitle.as_str().to_owned())
+            .unwrap_or_default(),
+        last_build_date: Some(feed.updated().to_rfc2822()),
+        items: feed.entries().iter().map(entry_to_item).collect(),
+        ..Default::default()
+    };
+
+    Ok(channel)
+}
+
+/// Turns an Atom entry into an RSS item.
+fn entry_to_item(entry: &Entry) -> Item {
+    let link = alternate_link(entry.links());
+
+    // Atom requires an id on every entry, so it becomes our GUID.
+    let guid = Guid {
+        permalink: link.as_deref() == Some(entry.id()),
+        value: entry.id().to_owned(),
+    };
+
+    // Keep the first enclosure, RSS only has room for one.
+    let enclosure = entry
+        .links()
+        .iter()
+        .find(|link| link.rel() == "enclosure")
+        .map(|link| Enclosure {
+            url: link.href().to_owned(),
+            length: link.length().unwrap_or("0").to_owned(),
+            mime_type: link.mime_type().unwrap_or_default().to_owned(),
+        });
+
+    let categories = entry
+        .categories()
+        .iter()
+        .map(|category| Category {
+            name: category.label().unwrap_or(category.term()).to_owned(),
+            domain: category.scheme().map(str::to_owned),
+        })
+        .collect();
+
+    // RSS authors are a single string, so keep every name in Dublin Core as well.
+    let author = entry.authors().first().map(person_to_author);
+    let dublin_core_ext = (!entry.authors().is_empty()).then(|| DublinCoreExtension {
+        creators: entry
+            .authors()
+            .iter()
+            .map(|person| person.name().to_owned())
+            .collect(),
+        ..Default::default()
+    });
+
+    Item {
+        title: Some(entry.title().as_str().to_owned()),
+        link,
+        description: entry.summary().map(|summary| summary.as_str().to_owned()),
+        author,
+        categories,
+        enclosure,
+        guid: Some(guid),
+        pub_date: Some(entry.published().unwrap_or(entry.updated()).to_rfc2822()),
+        content: entry
+            .content()
+            .and_then(|content| content.value())
+            .map(str::to_owned),
+        dublin_core_ext,
+        ..Default::default()
+    }
+}
+
+/// Renders a stored feed as an Atom document.
+///
+/// `self_url` is the address the feed is being served at.
+pub fn write_feed(feed: &StoredFeed, self_url: &str) -> String {
+    let updated = feed.updated();
+
+    let atom_feed = Feed {
+        title: Text::plain(&feed.title),
+        id: self_url.to_owned(),
+        updated,
+        subtitle: (!feed.description.is_empty()).then(|| Text::plain(&feed.description)),
+        links: vec![Link {
+            href: self_url.to_owned(),
+            rel: "self".to_owned(),
+            mime_type: Some(MIME_TYPE.to_owned()),
+            ..Default::default()
+        }],
+        entries: feed
+            .items
+            .iter()
+            .map(|item| item_to_entry(item, updated))
+            .collect(),
+        ..Default::default()
+    };
+
+    atom_feed.to_string()
+}
+
+/// Turns an RSS item into an Atom entry.
+///
+/// Items without a publication date are stamped with `fallback_updated`.
+fn item_to_entry(item: &Item, fallback_updated: atom_syndication::FixedDateTime) -> Entry {
+    let published = item_pub_date(item);
+
+    let mut links = Vec::new();
+    if let Some(link) = item.link() {
+        links.push(Link {
+            href: link.to_owned(),
+            rel: "alternate".to_owned(),
+            ..Default::default()
+        });
+    }
+    if let Some(enclosure) = item.enclosure() {
+        links.push(Link {
+            href: enclosure.url().to_owned(),
+            rel: "enclosure".to_owned(),
+            mime_type: Some(enclosure.mime_type().to_owned()),
+            length: Some(enclosure.length().to_owned()),
+            ..Default::default()
+        });
+    }
+
+    // Prefer the full list of names from Dublin Core over the single RSS author.
+    let authors = match item.dublin_core_ext() {
+        Some(dublin_core) if !dublin_core.creators().is_empty() => dublin_core
+            .creators()
+            .iter()
+            .map(|name| Person {
+                name: name.clone(),
+                ..Default::default()
+            })
+            .collect(),
+        _ => item.author().map(author_to_person).into_iter().collect(),
+    };
+
+    let categories = item
+        .categories()
+        .iter()
+        .map(|category| atom_syndication::Category {
+            term: category.name().to_owned(),
+            scheme: category.domain().map(str::to_owned),
+            label: None,
+        })
+        .collect();
+
+    Entry {
+        title: Text::plain(item.title().unwrap_or_default()),
+        id: item_to_guid(item),
+        updated: published.unwrap_or(fallback_updated),
+        authors,
+        categories,
+        links,
+        published,
+        summary: item.description().map(Text::html),
+        content: item.content().map(|content| Content {
+            value: Some(content.to_owned()),
+            content_type: Some("html".to_owned()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Picks the `alternate` link from a list of Atom links, or the first one if there is none.
+fn alternate_link(links: &[Link]) -> Option<String> {
+    links
+        .iter()
+        .find(|link| link.rel() == "alternate")
+        .or_else(|| links.first())
+        .map(|link| link.href().to_owned())
+}
+
+/// Formats an Atom person the way RSS expects an author, i.e. `email (name)`.
+fn person_to_author(person: &Person) -> String {
+    match person.email() {
+        Some(email) => format!("{email} ({})", person.name()),
+        None => person.name().to_owned(),
+    }
+}
+
+/// Splits an RSS author of the form `email (name)` back into an Atom person.
+fn author_to_person(author: &str) -> Person {
+    match author.split_once(" (") {
+        Some((email, name)) if name.ends_with(')') => Person {
+            name: name.trim_end_matches(')').to_owned(),
+            email: Some(email.to_owned()),
+            ..Default::default()
+        },
+        _ => Person {
+            name: author.to_owned(),
+            ..Default::default()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    const FEED_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Example Releases</title>
+  <subtitle>Release notes</subtitle>
+  <link href="https://example.com/releases" rel="alternate"/>
+  <link href="https://example.com/releases.atom" rel="self"/>
+  <id>tag:example.com,2025:releases</id>
+  <updated>2025-07-10T12:00:00Z</updated>
+  <entry>
+    <title>v1.2.0</title>
+    <link href="https://example.com/releases/v1.2.0" rel="alternate"/>
+    <link href="https://example.com/v1.2.0.tar.gz" rel="enclosure" type="application/gzip" length="1024"/>
+    <id>tag:example.com,2025:v1.2.0</id>
+    <published>2025-07-09T11:28:42Z</published>
+    <updated>2025-07-10T08:00:00Z</updated>
+    <author><name>Jane Doe</name><email>jane@example.com</email></author>
+    <category term="release" scheme="https://example.com/tags"/>
+    <summary>A new release.</summary>
+    <content type="html">&lt;p&gt;Lots of fixes.&lt;/p&gt;</content>
+  </entry>
+</feed>"#;
+
+    #[test]
+    fn test_read_channel() {
+        let channel = super::read_channel(FEED_XML.as_bytes()).expect("Failed to parse Atom feed");
+        assert_eq!(channel.title(), "Example Releases");
+        assert_eq!(channel.description(), "Release notes");
+        assert_eq!(channel.link(), "https://example.com/releases");
+
+        let item = channel.items().first().expect("No items in feed");
+        assert_eq!(item.title(), Some("v1.2.0"));
+        assert_eq!(item.link(), Some("https://example.com/releases/v1.2.0"));
+        assert_eq!(item.guid().unwrap().value(), "tag:example.com,2025:v1.2.0");
+        assert_eq!(item.author(), Some("jane@example.com (Jane Doe)"));
+        assert_eq!(item.description(), Some("A new release."));
+        assert_eq!(item.content(), Some("<p>Lots of fixes.</p>"));
+        assert_eq!(item.pub_date(), Some("Wed, 9 Jul 2025 11:28:42 +0000"));
+        assert_eq!(item.categories()[0].name(), "release");
+        assert_eq!(item.enclosure().unwrap().mime_type(), "application/gzip");
+        assert_eq!(
+            item.dublin_core_ext().unwrap().creators(),
+            &["Jane Doe".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_write_feed_round_trip() {
+        let channel = super::read_channel(FEED_XML.as_bytes()).expect("Failed to parse Atom feed");
+        let feed = crate::storage::StoredFeed {
+            title: channel.title().to_owned(),
+            description: channel.description().to_owned(),
+            items: channel.items().iter().cloned().collect(),
+        };
+
+        let self_url = "http://localhost:8080/releases.atom";
+        let written = super::write_feed(&feed, self_url);
+        let reread = atom_syndication::Feed::read_from(written.as_bytes())
+            .expect("Failed to parse written Atom feed");
+
+        assert_eq!(reread.id(), self_url);
+        assert_eq!(reread.links()[0].rel(), "self");
+        assert_eq!(reread.updated().to_rfc3339(), "2025-07-09T11:28:42+00:00");
+
+        let entry = reread.entries().first().expect("No entries in feed");
+        assert_eq!(entry.id(), "tag:example.com,2025:v1.2.0");
+        assert_eq!(entry.authors()[0].name(), "Jane Doe");
+        assert_eq!(
+            entry.content().unwrap().value(),
+            Some("<p>Lots of fixes.</p>")
+        );
+    }
+}
@@ -1,5 +1,10 @@
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
@@ -8,17 +13,363 @@ pub struct Config {
     pub feeds: HashMap<String, FeedConfig>,
     pub server_host: String,
     pub server_port: u16,
-    pub polling_interval_seconds: u64,
+    pub polling_interval_seconds: NonZeroU64,
     pub max_items_per_feed: usize,
     pub known_items_file: PathBuf,
+
+    /// Where to keep the items we're serving across restarts.
+    #[serde(default = "default_filtered_items_file")]
+    pub filtered_items_file: PathBuf,
+
+    /// Where to keep the LLM's answers across restarts.
+    #[serde(default = "default_decision_cache_file")]
+    pub decision_cache_file: PathBuf,
+
+    /// How many items rejected by the filter to keep for review, per feed.
+    #[serde(default = "default_max_rejected_items_per_feed")]
+    pub max_rejected_items_per_feed: usize,
+
+    /// Where to keep the items rejected by the filter across restarts.
+    #[serde(default = "default_rejected_items_file")]
+    pub rejected_items_file: PathBuf,
+
+    /// How to show the filter's decision in the items we serve.
+    #[serde(default)]
+    pub annotate_items: Vec<Annotation>,
+
+    /// What to do with items the LLM couldn't decide on (default: accept).
+    #[serde(default)]
+    pub on_llm_error: LLMErrorPolicy,
+
+    /// How long to keep retrying deferred items before giving up on them (default: 1 day).
+    ///
+    /// They're then rejected, unless the policy has since been changed to accept them.
+    #[serde(default = "default_max_defer_seconds")]
+    pub max_defer_seconds: u64,
+
+    /// Where to keep the LLM's token usage and costs across restarts.
+    #[serde(default = "default_usage_file")]
+    pub usage_file: PathBuf,
+
+    /// Where to keep deferred items across restarts.
+    #[serde(default = "default_pending_items_file")]
+    pub pending_items_file: PathBuf,
+
+    /// Where to keep our data (default: memory).
+    #[serde(default)]
+    pub storage_backend: StorageBackend,
+
+    /// The database file for the `sqlite` storage backend.
+    #[serde(default = "default_database_file")]
+    pub database_file: PathBuf,
+
+    /// Settings for the HTTP client used to retrieve feeds.
+    #[serde(default)]
+    pub http: HttpConfig,
+
+    /// How many feeds can be retrieved at the same time.
+    #[serde(default = "default_max_concurrent_fetches")]
+    pub max_concurrent_fetches: NonZeroUsize,
+
+    /// How many requests can be made to the LLM at the same time.
+    #[serde(default = "default_max_concurrent_llm_requests")]
+    pub max_concurrent_llm_requests: NonZeroUsize,
+
+    /// The URL readers reach us at, for feeds to link to themselves.
+    ///
+    /// Worked out from each request's (forwarded) scheme and host when unset.
+    pub public_url: Option<url::Url>,
+}
+
+fn default_filtered_items_file() -> PathBuf {
+    PathBuf::from("filtered_items.json")
+}
+
+fn default_decision_cache_file() -> PathBuf {
+    PathBuf::from("decision_cache.json")
+}
+
+fn default_max_rejected_items_per_feed() -> usize {
+    50
+}
+
+fn default_rejected_items_file() -> PathBuf {
+    PathBuf::from("rejected_items.json")
+}
+
+fn default_usage_file() -> PathBuf {
+    PathBuf::from("llm_usage.json")
+}
+
+fn default_max_defer_seconds() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_pending_items_file() -> PathBuf {
+    PathBuf::from("pending_items.json")
+}
+
+fn default_database_file() -> PathBuf {
+    PathBuf::from("sane-rss.db")
+}
+
+fn default_max_concurrent_fetches() -> NonZeroUsize {
+    NonZeroUsize::new(8).unwrap()
+}
+
+fn default_max_concurrent_llm_requests() -> NonZeroUsize {
+    NonZeroUsize::new(4).unwrap()
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct LLMConfig {
     pub provider: String,
-    pub api_key: String,
+
+    /// Required by hosted providers, optional for local ones.
+    pub api_key: Option<String>,
+
+    /// Where to reach the provider, e.g. `http://localhost:11434` for Ollama or
+    /// `http://localhost:8000/v1` for an OpenAI-compatible server.
+    pub base_url: Option<String>,
+
     pub model: String,
+
+    /// A MiniJinja template for the prompt about each item. The old `{title}` style still works.
     pub prompt: String,
+
+    /// A system prompt to send along with every request.
+    pub system_prompt: Option<String>,
+
+    /// Sampling temperature; leave unset for the provider's default.
+    pub temperature: Option<f32>,
+
+    /// The most tokens the LLM may answer with; leave unset for the provider's default.
+    pub max_tokens: Option<u32>,
+
+    /// Whether to let the model think before answering, on providers that allow turning it on
+    /// (Anthropic), within a budget of thinking tokens (default: false).
+    ///
+    /// Needs `max_tokens` above `thinking_budget_tokens`, and Anthropic a `temperature` of 1.
+    #[serde(default)]
+    pub thinking_enabled: bool,
+    pub thinking_budget_tokens: Option<u32>,
+
+    /// How hard reasoning models should think, on providers that support it (OpenAI and
+    /// OpenAI-compatible servers).
+    pub reasoning_effort: Option<ReasoningEffort>,
+
+    /// What the provider charges per million prompt and completion tokens (default: 0).
+    #[serde(default)]
+    pub input_cost_per_million_tokens: f64,
+    #[serde(default)]
+    pub output_cost_per_million_tokens: f64,
+
+    /// How many requests, and how many tokens, to send to the provider each minute (default: no limit).
+    pub requests_per_minute: Option<NonZeroU32>,
+    pub tokens_per_minute: Option<NonZeroU32>,
+
+    /// The most to spend on the LLM in a (UTC) day and month, in the currency of the costs above.
+    ///
+    /// Once spent, items are handled as if the LLM had failed, following `on_llm_error`.
+    pub daily_budget: Option<f64>,
+    pub monthly_budget: Option<f64>,
+
+    /// How many items to send to the LLM in one request (default: 1, no batching).
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+
+    /// The prompt template for batches of items, looping over `posts`, each with an `id`.
+    #[serde(default = "default_batch_prompt")]
+    pub batch_prompt: String,
+
+    /// How long to reuse the LLM's answer for an item it has seen before (default: 30 days).
+    #[serde(default = "default_cache_ttl_seconds")]
+    pub cache_ttl_seconds: u64,
+
+    /// How many answers to keep in the cache; 0 disables it (default: 10000).
+    #[serde(default = "default_cache_max_entries")]
+    pub cache_max_entries: usize,
+
+    /// Providers to try in order when the one above fails.
+    #[serde(default)]
+    pub fallbacks: Vec<LLMProviderConfig>,
+
+    /// How many times to retry a provider on rate limits, server errors and timeouts (default: 2).
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+
+    /// How long to wait before the first retry, doubling with each one (default: 1000).
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+
+    /// How long to wait for the LLM to answer a request (default: 120).
+    #[serde(default = "default_llm_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+impl LLMConfig {
+    /// Returns the providers to try for a feed, in order: the configured one, with any of the
+    /// feed's overrides, followed by the fallbacks.
+    ///
+    /// A feed that switches provider doesn't inherit the API key, base URL or rate limits, and
+    /// one that switches model doesn't inherit the costs.
+    pub fn providers(&self, overrides: Option<&FeedLLMConfig>) -> Vec<LLMProviderConfig> {
+        let mut primary = LLMProviderConfig {
+            provider: self.provider.clone(),
+            api_key: self.api_key.clone(),
+            base_url: self.base_url.clone(),
+            model: self.model.clone(),
+            input_cost_per_million_tokens: self.input_cost_per_million_tokens,
+            output_cost_per_million_tokens: self.output_cost_per_million_tokens,
+            requests_per_minute: self.requests_per_minute,
+            tokens_per_minute: self.tokens_per_minute,
+        };
+
+        if let Some(overrides) = overrides {
+            if let Some(provider) = &overrides.provider
+                && *provider != primary.provider
+            {
+                primary.provider = provider.clone();
+                primary.api_key = None;
+                primary.base_url = None;
+                primary.requests_per_minute = None;
+                primary.tokens_per_minute = None;
+            }
+            if primary.provider != self.provider
+                || overrides
+                    .model
+                    .as_ref()
+                    .is_some_and(|model| *model != self.model)
+            {
+                primary.input_cost_per_million_tokens = 0.0;
+                primary.output_cost_per_million_tokens = 0.0;
+            }
+
+            primary.model = overrides.model.clone().unwrap_or(primary.model);
+            primary.api_key = overrides.api_key.clone().or(primary.api_key);
+            primary.base_url = overrides.base_url.clone().or(primary.base_url);
+            primary.input_cost_per_million_tokens = overrides
+                .input_cost_per_million_tokens
+                .unwrap_or(primary.input_cost_per_million_tokens);
+            primary.output_cost_per_million_tokens = overrides
+                .output_cost_per_million_tokens
+                .unwrap_or(primary.output_cost_per_million_tokens);
+            primary.requests_per_minute = overrides
+                .requests_per_minute
+                .or(primary.requests_per_minute);
+            primary.tokens_per_minute = overrides.tokens_per_minute.or(primary.tokens_per_minute);
+        }
+
+        std::iter::once(primary)
+            .chain(self.fallbacks.iter().cloned())
+            .collect()
+    }
+}
+
+/// A provider and model the LLM filter can send requests to.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LLMProviderConfig {
+    pub provider: String,
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+    pub model: String,
+    #[serde(default)]
+    pub input_cost_per_million_tokens: f64,
+    #[serde(default)]
+    pub output_cost_per_million_tokens: f64,
+    pub requests_per_minute: Option<NonZeroU32>,
+    pub tokens_per_minute: Option<NonZeroU32>,
+}
+
+/// A feed's own provider, model and prompts, each falling back to the `[llm]` section when unset.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedLLMConfig {
+    pub provider: Option<String>,
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    pub prompt: Option<String>,
+    pub batch_prompt: Option<String>,
+    pub input_cost_per_million_tokens: Option<f64>,
+    pub output_cost_per_million_tokens: Option<f64>,
+    pub requests_per_minute: Option<NonZeroU32>,
+    pub tokens_per_minute: Option<NonZeroU32>,
+}
+
+/// How hard a reasoning model should think.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+impl ReasoningEffort {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        }
+    }
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_llm_timeout_seconds() -> u64 {
+    120
+}
+
+fn default_cache_ttl_seconds() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_cache_max_entries() -> usize {
+    10_000
+}
+
+fn default_batch_size() -> usize {
+    1
+}
+
+fn default_batch_prompt() -> String {
+    r#"You are an RSS feed filter. Analyze each of the following RSS posts and determine if it matches any of the provided topics.
+
+{% for post in posts %}
+Post id: {{ post.id }}
+Post title: {{ post.title or "none" }}
+{% if post.author %}
+Post author: {{ post.author }}
+{% endif %}
+Post description: {{ post.description or "none" }}
+Post content excerpt: {{ post.content_excerpt or "none" }}
+
+{% endfor %}
+Accept topics: {{ accept_topics | join("; ") or "none" }}
+Reject topics: {{ reject_topics | join("; ") or "none" }}
+
+Return a JSON response with a "decisions" list, holding one object per post with these fields:
+- "id": the post's id, as given above
+- "accept": true if the post matches any accept topics, otherwise false
+- "reject": true if the post matches any reject topics, otherwise false
+- "reason": one short sentence explaining your decision
+- "topics": the accept and reject topics the post matches, as a list of strings
+
+Both booleans can be true at the same time. If both are true, the post will be accepted.
+
+You must respond with valid JSON in exactly this format: {"decisions": [{"id": "1", "accept": true/false, "reject": true/false, "reason": "...", "topics": ["..."]}]}
+"#
+    .to_string()
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -31,4 +382,202 @@ pub struct Filters {
 pub struct FeedConfig {
     pub url: String,
     pub filters: Filters,
+
+    /// Overrides the global polling interval for this feed.
+    pub polling_interval_seconds: Option<NonZeroU64>,
+
+    /// Overrides the global HTTP client settings for this feed.
+    pub http: Option<HttpConfig>,
+
+    /// Credentials for private feeds.
+    pub auth: Option<FeedAuth>,
+
+    /// Overrides the global policy for items the LLM couldn't decide on.
+    pub on_llm_error: Option<LLMErrorPolicy>,
+
+    /// Overrides the global LLM provider, model and prompts for this feed.
+    pub llm: Option<FeedLLMConfig>,
+}
+
+/// Credentials to send along with a feed request.
+///
+/// Any combination can be set, except basic and bearer authentication together.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FeedAuth {
+    pub basic: Option<BasicAuth>,
+    pub bearer: Option<Secret>,
+    #[serde(default)]
+    pub headers: HashMap<String, Secret>,
+    pub cookie: Option<Secret>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Option<Secret>,
+}
+
+/// What to do with an item when the LLM can't be asked about it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LLMErrorPolicy {
+    /// Serve the item anyway.
+    #[default]
+    Accept,
+
+    /// Keep the item aside with the rejected ones.
+    Reject,
+
+    /// Hold the item, and ask again on the feed's next polls.
+    Defer,
+}
+
+/// A way of showing the filter's decision in a served item.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Annotation {
+    /// Append the LLM's reason, and the topics it matched, to the item's description.
+    Description,
+
+    /// Add the topics the LLM matched as categories.
+    Categories,
+}
+
+/// Where we keep our feeds, items and decisions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// In memory, written to the JSON files in the configuration.
+    #[default]
+    Memory,
+
+    /// In an SQLite database.
+    Sqlite,
+}
+
+/// A secret value, given inline or loaded from an environment variable or a file.
+///
+/// In TOML: `"value"`, `{ env = "VARIABLE" }` or `{ file = "/path/to/file" }`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Inline(String),
+    Env { env: String },
+    File { file: PathBuf },
+}
+
+impl Secret {
+    /// Returns the secret's value.
+    ///
+    /// Trailing newlines are trimmed from values read from files.
+    pub fn resolve(&self) -> anyhow::Result<String> {
+        match self {
+            Secret::Inline(value) => Ok(value.clone()),
+            Secret::Env { env } => std::env::var(env)
+                .with_context(|| format!("Failed to read secret from environment variable {env}")),
+            Secret::File { file } => std::fs::read_to_string(file)
+                .map(|value| value.trim_end_matches(['\r', '\n']).to_owned())
+                .with_context(|| format!("Failed to read secret from file {}", file.display())),
+        }
+    }
+}
+
+/// HTTP client settings. Anything left unset falls back to a sensible default.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HttpConfig {
+    pub connect_timeout_seconds: Option<u64>,
+    pub read_timeout_seconds: Option<u64>,
+    pub timeout_seconds: Option<u64>,
+    pub user_agent: Option<String>,
+    /// An `http://`, `https://` or `socks5://` proxy URL.
+    pub proxy: Option<String>,
+    /// Whether to ask for gzip/brotli compressed responses.
+    pub compression: Option<bool>,
+    pub max_response_bytes: Option<u64>,
+    pub max_redirects: Option<usize>,
+}
+
+impl HttpConfig {
+    /// Returns these settings with any set in `overrides` taking precedence.
+    pub fn merged_with(&self, overrides: &HttpConfig) -> HttpConfig {
+        HttpConfig {
+            connect_timeout_seconds: overrides
+                .connect_timeout_seconds
+                .or(self.connect_timeout_seconds),
+            read_timeout_seconds: overrides.read_timeout_seconds.or(self.read_timeout_seconds),
+            timeout_seconds: overrides.timeout_seconds.or(self.timeout_seconds),
+            user_agent: overrides.user_agent.clone().or(self.user_agent.clone()),
+            proxy: overrides.proxy.clone().or(self.proxy.clone()),
+            compression: overrides.compression.or(self.compression),
+            max_response_bytes: overrides.max_response_bytes.or(self.max_response_bytes),
+            max_redirects: overrides.max_redirects.or(self.max_redirects),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, FeedLLMConfig};
+
+    #[test]
+    fn test_sample_config() {
+        let sample = include_str!("../config/sample.toml");
+        let config: Config = toml::from_str(sample).unwrap();
+        assert!(!config.llm.thinking_enabled);
+
+        // Typos in [llm] are caught rather than ignored.
+        let typo = sample.replace("thinking_enabled", "thinking_enabeld");
+        let error = toml::from_str::<Config>(&typo).unwrap_err();
+        assert!(error.to_string().contains("thinking_enabeld"));
+
+        // A limit of 0 would stall every request, so it's refused rather than taken literally.
+        let zero = sample.replace("# requests_per_minute = 500", "requests_per_minute = 0");
+        assert!(toml::from_str::<Config>(&zero).is_err());
+        let zero = sample.replace("max_concurrent_fetches = 8", "max_concurrent_fetches = 0");
+        assert!(toml::from_str::<Config>(&zero).is_err());
+        let zero = sample.replace(
+            "polling_interval_seconds = 300",
+            "polling_interval_seconds = 0",
+        );
+        assert!(toml::from_str::<Config>(&zero).is_err());
+        let zero = sample.replace(
+            "polling_interval_seconds = 900",
+            "polling_interval_seconds = 0",
+        );
+        assert!(toml::from_str::<Config>(&zero).is_err());
+
+        let typo = sample.replace(
+            r#"storage_backend = "memory""#,
+            r#"storage_backend = "sqlit""#,
+        );
+        assert!(toml::from_str::<Config>(&typo).is_err());
+    }
+
+    #[test]
+    fn test_feed_llm_overrides() {
+        let sample = include_str!("../config/sample.toml");
+        let config: Config = toml::from_str(sample).unwrap();
+
+        // Feeds without overrides use [llm] as it is.
+        let providers = config.llm.providers(None);
+        assert_eq!(providers[0].model, "gpt-5-2025-08-07");
+        assert_eq!(providers[0].input_cost_per_million_tokens, 1.25);
+
+        let arxiv = config.feeds["arxiv"].llm.as_ref();
+        let providers = config.llm.providers(arxiv);
+        assert_eq!(providers[0].model, "gpt-5-pro-2025-10-06");
+        assert_eq!(providers[0].api_key.as_deref(), Some("API_KEY"));
+        assert_eq!(providers[0].input_cost_per_million_tokens, 15.0);
+
+        // Switching provider leaves the other provider's key and costs behind.
+        let overrides = FeedLLMConfig {
+            provider: Some("ollama".to_owned()),
+            model: Some("llama3.1".to_owned()),
+            ..Default::default()
+        };
+        let providers = config.llm.providers(Some(&overrides));
+        assert_eq!(providers[0].provider, "ollama");
+        assert_eq!(providers[0].api_key, None);
+        assert_eq!(providers[0].output_cost_per_million_tokens, 0.0);
+    }
 }
@@ -1,192 +1,1101 @@
 //! LLM-based feed filter.
 
-use crate::config::Config;
+mod cache;
+mod openai_compatible;
+mod prompt;
+mod rate_limit;
+
+pub use cache::DecisionCache;
+
+use openai_compatible::OpenAICompatible;
+use prompt::{BatchPrompt, ItemFields, ItemPrompt, Post, PromptTemplate};
+use rate_limit::{RateLimiter, estimate_tokens, retry_after};
+
+use crate::config::{Annotation, Config, LLMConfig, LLMProviderConfig, ReasoningEffort};
+use crate::metrics::Metrics;
+use crate::usage::{UsageTotals, UsageTracker};
 use anyhow::{Context, bail};
+use chrono::{DateTime, Utc};
+use futures::future;
 use llm::{
     LLMProvider,
     builder::{LLMBackend, LLMBuilder},
-    chat::ChatMessage,
+    chat::{ChatMessage, ChatResponse, StructuredOutputFormat},
+    error::LLMError,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// How much of an item's content to give the LLM as an excerpt, in bytes.
+const CONTENT_EXCERPT_BYTES: usize = 1000;
 
 pub struct LLMFilter {
-    llm: Box<dyn LLMProvider>,
+    /// Every provider and model any feed uses, set up once, so feeds sharing one share its
+    /// rate limits.
+    providers: HashMap<ProviderKey, Arc<Provider>>,
+
+    /// The providers and prompts each feed's items are sent with, by feed name.
+    feeds: HashMap<String, FeedLLM>,
+
     config: Config,
+
+    /// Bounds how many requests we make to the LLM at the same time.
+    request_permits: Semaphore,
+
+    /// Answers the LLM has already given.
+    cache: DecisionCache,
+
+    metrics: Metrics,
+
+    /// What we've spent on the LLM, and how much we may.
+    usage: UsageTracker,
 }
 
 /// A result from the LLM filter query.
-#[derive(Debug, Deserialize)]
-struct FilterResponse {
-    accept: bool,
-    reject: bool,
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FilterResponse {
+    pub accept: bool,
+    pub reject: bool,
+
+    /// Why the LLM decided as it did, if it said.
+    #[serde(default)]
+    pub reason: Option<String>,
+
+    /// The accept and reject topics the LLM found in the item.
+    #[serde(default)]
+    pub topics: Vec<String>,
 }
 
-impl LLMFilter {
-    pub fn new(config: Config) -> anyhow::Result<Self> {
-        let backend = match config.llm.provider.as_str() {
-            "anthropic" => LLMBackend::Anthropic,
-            "gemini" => LLMBackend::Google,
-            "openai" => LLMBackend::OpenAI,
-            _ => bail!("Invalid LLM provider in configuration"),
+/// The filter's verdict on an item.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FilterDecision {
+    pub accepted: bool,
+
+    /// What the LLM answered, if it was asked.
+    pub response: Option<FilterResponse>,
+
+    pub decided_at: DateTime<Utc>,
+}
+
+impl FilterDecision {
+    /// An acceptance made without asking the LLM.
+    fn auto_accept() -> Self {
+        Self::without_llm(true)
+    }
+
+    /// A decision made without the LLM's answer, such as when it couldn't be asked.
+    pub fn without_llm(accepted: bool) -> Self {
+        Self {
+            accepted,
+            response: None,
+            decided_at: Utc::now(),
+        }
+    }
+
+    /// Shows the LLM's answer in an item, in the ways asked for.
+    pub fn annotate(&self, item: &mut rss::Item, annotations: &[Annotation]) {
+        let Some(response) = &self.response else {
+            return;
         };
 
-        let llm = LLMBuilder::new()
-            .backend(backend)
-            .api_key(&config.llm.api_key)
-            .model(&config.llm.model)
-            .build()
-            .unwrap();
+        if annotations.contains(&Annotation::Description) {
+            let mut note = response.reason.clone().unwrap_or_default();
+            if !response.topics.is_empty() {
+                if !note.is_empty() {
+                    note.push(' ');
+                }
+                note.push_str(&format!("(topics: {})", response.topics.join(", ")));
+            }
 
-        Ok(Self { llm, config })
+            if !note.is_empty() {
+                let note = format!("<p>Filter: {}</p>", escape_html(&note));
+                let description = format!("{}{note}", item.description().unwrap_or_default());
+                item.set_description(description);
+            }
+        }
+
+        if annotations.contains(&Annotation::Categories) {
+            for topic in &response.topics {
+                if !item
+                    .categories()
+                    .iter()
+                    .any(|category| category.name() == topic)
+                {
+                    item.categories.push(rss::Category {
+                        name: topic.clone(),
+                        domain: None,
+                    });
+                }
+            }
+        }
     }
+}
 
-    /// Sends the item to the LLM for filtering.
-    ///
-    /// Returns true if the item should be accepted.
-    pub async fn accepts(&self, feed_name: &str, item: &rss::Item) -> bool {
-        tracing::debug!(
-            "Asking LLM if it accepts item from feed {feed_name}: {:?}",
-            item.title().unwrap_or("<no title>")
+/// A provider and model we can send requests to.
+struct Provider {
+    /// How the provider and model are named in logs and usage records.
+    name: String,
+
+    /// What the provider charges per million prompt and completion tokens.
+    input_cost_per_million_tokens: f64,
+    output_cost_per_million_tokens: f64,
+
+    /// Keeps us within the provider's rate limits, across all feeds.
+    limiter: RateLimiter,
+
+    /// The requests and tokens per minute the limiter was set up with.
+    limits: (Option<NonZeroU32>, Option<NonZeroU32>),
+
+    /// The system prompt and sampling settings the model is asked with, as they go into cache keys.
+    settings: String,
+
+    llm: Box<dyn LLMProvider>,
+
+    /// The LLM, set up to answer about several items at once, when batching is enabled.
+    batch_llm: Option<Box<dyn LLMProvider>>,
+}
+
+/// What sets one provider apart from another.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ProviderKey {
+    provider: String,
+    base_url: Option<String>,
+    api_key: Option<String>,
+    model: String,
+}
+
+/// How a feed's items are put to the LLM.
+struct FeedLLM {
+    /// The providers to ask, in order, each tried until one answers.
+    providers: Vec<ProviderKey>,
+
+    prompt: Arc<PromptTemplate>,
+    batch_prompt: Arc<PromptTemplate>,
+}
+
+/// The accept and reject topics that apply to a feed.
+struct Topics {
+    accept: Vec<String>,
+    reject: Vec<String>,
+}
+
+/// Stops a request from being sent once an LLM budget has been used up.
+#[derive(Debug)]
+struct BudgetExceeded(&'static str);
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "LLM {} budget exceeded", self.0)
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+/// One item's verdict in a batched LLM response.
+#[derive(Debug, Deserialize)]
+struct BatchDecision {
+    id: String,
+    #[serde(flatten)]
+    response: FilterResponse,
+}
+
+/// A result from a batched LLM filter query.
+#[derive(Debug, Deserialize)]
+struct BatchResponse {
+    decisions: Vec<BatchDecision>,
+}
+
+impl LLMFilter {
+    pub fn new(
+        config: Config,
+        cache: DecisionCache,
+        metrics: Metrics,
+        usage: UsageTracker,
+    ) -> anyhow::Result<Self> {
+        // Thinking tokens count towards max_tokens, and provider defaults leave no room for them.
+        if config.llm.thinking_enabled {
+            match (config.llm.max_tokens, config.llm.thinking_budget_tokens) {
+                (Some(max_tokens), Some(budget)) if max_tokens > budget => (),
+                _ => bail!(
+                    "LLM thinking needs max_tokens above thinking_budget_tokens in configuration"
+                ),
+            }
+        }
+
+        let prompt = Arc::new(
+            PromptTemplate::item(&config.llm.prompt)
+                .context("Invalid LLM prompt in configuration")?,
+        );
+        let batch_prompt = Arc::new(
+            PromptTemplate::batch(&config.llm.batch_prompt)
+                .context("Invalid LLM batch_prompt in configuration")?,
         );
 
+        let mut providers: HashMap<ProviderKey, Arc<Provider>> = HashMap::new();
+        let mut feeds = HashMap::new();
+        for (feed_name, feed_config) in &config.feeds {
+            let overrides = feed_config.llm.as_ref();
+
+            let mut keys = Vec::new();
+            for (index, provider) in config.llm.providers(overrides).iter().enumerate() {
+                let key = ProviderKey::new(provider);
+                match providers.get(&key) {
+                    // Feeds sharing a provider share its limiter, so they must agree on its limits.
+                    Some(existing) => {
+                        if existing.limits
+                            != (provider.requests_per_minute, provider.tokens_per_minute)
+                        {
+                            bail!(
+                                "Conflicting rate limits for LLM {} for feed {feed_name} in configuration",
+                                existing.name
+                            );
+                        }
+                    }
+                    None => {
+                        let built =
+                            Provider::new(provider, &config.llm).with_context(|| match index {
+                                0 => format!("Failed to set up the LLM for feed {feed_name}"),
+                                _ => format!(
+                                    "Failed to set up LLM fallback {index} for feed {feed_name}"
+                                ),
+                            })?;
+                        providers.insert(key.clone(), Arc::new(built));
+                    }
+                }
+                keys.push(key);
+            }
+
+            let feed_llm = FeedLLM {
+                providers: keys,
+                prompt: match overrides.and_then(|overrides| overrides.prompt.as_ref()) {
+                    Some(source) => Arc::new(PromptTemplate::item(source).with_context(|| {
+                        format!("Invalid LLM prompt for feed {feed_name} in configuration")
+                    })?),
+                    None => prompt.clone(),
+                },
+                batch_prompt: match overrides.and_then(|overrides| overrides.batch_prompt.as_ref())
+                {
+                    Some(source) => Arc::new(PromptTemplate::batch(source).with_context(|| {
+                        format!("Invalid LLM batch_prompt for feed {feed_name} in configuration")
+                    })?),
+                    None => batch_prompt.clone(),
+                },
+            };
+            feeds.insert(feed_name.clone(), feed_llm);
+        }
+
+        let request_permits = Semaphore::new(config.max_concurrent_llm_requests.get());
+
+        Ok(Self {
+            providers,
+            feeds,
+            config,
+            request_permits,
+            cache,
+            metrics,
+            usage,
+        })
+    }
+
+    /// Sends a feed's new items to the LLM for filtering.
+    ///
+    /// Returns whether each item should be accepted, along with the LLM's answer, in order, or
+    /// `None` for items the LLM couldn't decide on.
+    pub async fn decide_all(
+        &self,
+        feed_name: &str,
+        items: &[rss::Item],
+    ) -> Vec<Option<FilterDecision>> {
         //
-        // Prepare the list of accepted and rejected topics for this item.
+        // Prepare the list of accepted and rejected topics for this feed.
         let feed_config = self.config.feeds.get(feed_name).expect("Unknown feed name");
 
-        let mut accept_topics = Vec::new();
-        accept_topics.extend(self.config.global_filters.accept.clone());
-        accept_topics.extend(feed_config.filters.accept.clone());
+        let mut accept = Vec::new();
+        accept.extend(self.config.global_filters.accept.clone());
+        accept.extend(feed_config.filters.accept.clone());
 
-        let mut reject_topics = Vec::new();
-        reject_topics.extend(self.config.global_filters.reject.clone());
-        reject_topics.extend(feed_config.filters.reject.clone());
+        let mut reject = Vec::new();
+        reject.extend(self.config.global_filters.reject.clone());
+        reject.extend(feed_config.filters.reject.clone());
 
-        if accept_topics.is_empty() && reject_topics.is_empty() {
+        if accept.is_empty() && reject.is_empty() {
             tracing::debug!("No topics to accept or reject, auto-accepting");
-            return true;
+            return items
+                .iter()
+                .map(|_| Some(FilterDecision::auto_accept()))
+                .collect();
+        }
+        let topics = Topics { accept, reject };
+
+        // Answer what we can from the cache, with what the feed's primary provider said.
+        let provider = &self.feed_llm(feed_name).providers[0];
+        let mut decisions: Vec<Option<FilterDecision>> = items
+            .iter()
+            .map(|item| {
+                let response = self
+                    .cache
+                    .get(&self.cache_key(feed_name, provider, item, &topics))?;
+                Some(decision_from_response(item, response))
+            })
+            .collect();
+
+        let uncached: Vec<usize> = (0..items.len())
+            .filter(|&index| decisions[index].is_none())
+            .collect();
+        if uncached.len() < items.len() {
+            tracing::debug!(
+                "Answered {} items from feed {feed_name} from the cache",
+                items.len() - uncached.len()
+            );
         }
+        // Leave the rest undecided once we've spent what we may.
+        if !uncached.is_empty()
+            && let Some(budget) = self.usage.exceeded_budget()
+        {
+            tracing::warn!(
+                "LLM {budget} budget exceeded, leaving {} items from feed {feed_name} undecided",
+                uncached.len()
+            );
+            return decisions;
+        }
+
+        let uncached_items: Vec<&rss::Item> = uncached.iter().map(|&index| &items[index]).collect();
+
+        // Send everything else at once; `call_llm` bounds how many requests are in flight.
+        let answers: Vec<Option<FilterDecision>> = match self.config.llm.batch_size > 1 {
+            false => {
+                future::join_all(
+                    uncached_items
+                        .iter()
+                        .map(|item| self.decide(feed_name, item, &topics)),
+                )
+                .await
+            }
+            true => future::join_all(
+                uncached_items
+                    .chunks(self.config.llm.batch_size)
+                    .map(|batch| self.decide_batch(feed_name, batch, &topics)),
+            )
+            .await
+            .into_iter()
+            .flatten()
+            .collect(),
+        };
+
+        for (index, decision) in uncached.into_iter().zip(answers) {
+            decisions[index] = decision;
+        }
+
+        decisions
+    }
+
+    /// Writes the LLM's cached answers to disk, on a blocking thread.
+    pub async fn save_cache(&self) -> anyhow::Result<()> {
+        let cache = self.cache.clone();
+        tokio::task::spawn_blocking(move || cache.save())
+            .await
+            .context("Saving the decision cache panicked")?
+    }
+
+    /// Writes what we've spent on the LLM to disk, on a blocking thread.
+    pub async fn save_usage(&self) -> anyhow::Result<()> {
+        let usage = self.usage.clone();
+        tokio::task::spawn_blocking(move || usage.save())
+            .await
+            .context("Saving LLM usage panicked")?
+    }
+
+    /// Returns how a feed's items are put to the LLM.
+    fn feed_llm(&self, feed_name: &str) -> &FeedLLM {
+        self.feeds.get(feed_name).expect("Unknown feed name")
+    }
+
+    /// Hashes everything that goes into asking a provider about an item.
+    ///
+    /// Items seen in several feeds share an answer, unless the prompt names the feed.
+    fn cache_key(
+        &self,
+        feed_name: &str,
+        provider: &ProviderKey,
+        item: &rss::Item,
+        topics: &Topics,
+    ) -> String {
+        let feed_llm = self.feed_llm(feed_name);
+        let template = match self.config.llm.batch_size > 1 {
+            true => &feed_llm.batch_prompt,
+            false => &feed_llm.prompt,
+        };
+        let feed_name = match template.uses_feed() {
+            true => feed_name,
+            false => "",
+        };
+        let fields = serde_json::to_string(&ItemFields::new(item)).unwrap_or_default();
+
+        DecisionCache::key(&[
+            &provider.provider,
+            provider.base_url.as_deref().unwrap_or_default(),
+            &provider.model,
+            &self.providers[provider].settings,
+            template.source(),
+            feed_name,
+            &topics.accept.join("\n"),
+            &topics.reject.join("\n"),
+            &fields,
+        ])
+    }
+
+    /// Sends a single item to the LLM for filtering.
+    ///
+    /// Returns `None` if the LLM couldn't be asked, or gave no usable answer.
+    async fn decide(
+        &self,
+        feed_name: &str,
+        item: &rss::Item,
+        topics: &Topics,
+    ) -> Option<FilterDecision> {
+        tracing::debug!(
+            "Asking LLM if it accepts item from feed {feed_name}: {:?}",
+            item.title().unwrap_or("<no title>")
+        );
 
         // Prepare a prompt.
-        let prompt = self.prepare_prompt(item, accept_topics, reject_topics);
+        let prompt = match self.prepare_prompt(feed_name, item, topics) {
+            Ok(prompt) => prompt,
+            Err(error) => {
+                tracing::warn!("Failed to prepare a prompt for the LLM: {error:#}");
+                return None;
+            }
+        };
 
-        // Call the LLM.
-        match self.call_llm(prompt).await {
+        // Call the LLM, and remember its answer under the provider that gave it.
+        match self
+            .call_llm::<FilterResponse>(feed_name, false, prompt)
+            .await
+        {
             Err(error) => {
-                tracing::warn!("Failed to chat with the LLM, auto-accepting item: {error}");
-                true
+                tracing::warn!("Failed to get a decision from the LLM: {error}");
+                None
             }
+            Ok((response, provider)) => {
+                let key = self.cache_key(feed_name, provider, item, topics);
+                self.cache.insert(key, response.clone());
+                Some(decision_from_response(item, response))
+            }
+        }
+    }
 
-            Ok(response) => {
-                if response.reject {
-                    tracing::info!("LLM filter rejected '{:?}'", item.title());
+    /// Sends several items to the LLM in one request.
+    ///
+    /// Batches too large for the model's context are split in half and tried again. Items the
+    /// LLM gives no usable answer for are sent again on their own. Items the LLM couldn't be
+    /// asked about at all are left undecided.
+    async fn decide_batch(
+        &self,
+        feed_name: &str,
+        items: &[&rss::Item],
+        topics: &Topics,
+    ) -> Vec<Option<FilterDecision>> {
+        let mut decisions: Vec<Option<FilterDecision>> = vec![None; items.len()];
+        let mut unanswered = Vec::new();
+        let mut batches = Vec::new();
+        batches.push(0..items.len());
+
+        while let Some(batch) = batches.pop() {
+            tracing::debug!(
+                "Asking LLM about a batch of {} items from feed {feed_name}",
+                batch.len()
+            );
+            let prompt = self.prepare_batch_prompt(feed_name, &items[batch.clone()], topics);
+
+            let response = match prompt {
+                Ok(prompt) => {
+                    self.call_llm::<BatchResponse>(feed_name, true, prompt)
+                        .await
+                }
+                Err(error) => Err(error),
+            };
+            match response {
+                Ok((response, provider)) => {
+                    // Ids are positions within the batch, counting from 1.
+                    for decision in response.decisions {
+                        let position = decision.id.trim().parse::<usize>().ok();
+                        if let Some(index) = position
+                            .filter(|position| (1..=batch.len()).contains(position))
+                            .map(|position| batch.start + position - 1)
+                        {
+                            let key = self.cache_key(feed_name, provider, items[index], topics);
+                            self.cache.insert(key, decision.response.clone());
+                            decisions[index] =
+                                Some(decision_from_response(items[index], decision.response));
+                        }
+                    }
+                    unanswered.extend(batch.filter(|&index| decisions[index].is_none()));
                 }
-                tracing::debug!("LLM filter decisions: {:?}", response);
 
-                response.accept || !response.reject
+                Err(error) if is_context_length_error(&error) && batch.len() > 1 => {
+                    tracing::debug!("Batch too large for the LLM, splitting it: {error}");
+                    let middle = batch.start + batch.len() / 2;
+                    batches.push(middle..batch.end);
+                    batches.push(batch.start..middle);
+                }
+
+                Err(error) if error.is::<LLMError>() || error.is::<BudgetExceeded>() => {
+                    tracing::warn!("Failed to get decisions from the LLM: {error}");
+                }
+
+                Err(error) => {
+                    tracing::warn!("Malformed batch response, asking about each item: {error}");
+                    unanswered.extend(batch);
+                }
             }
         }
+
+        if !unanswered.is_empty() {
+            let retries = future::join_all(
+                unanswered
+                    .iter()
+                    .map(|&index| self.decide(feed_name, items[index], topics)),
+            )
+            .await;
+            for (index, decision) in unanswered.into_iter().zip(retries) {
+                decisions[index] = decision;
+            }
+        }
+
+        decisions
     }
 
-    async fn call_llm(&self, prompt: String) -> anyhow::Result<FilterResponse> {
+    /// Sends a prompt to each provider in turn until one answers, and parses its answer.
+    ///
+    /// Providers are retried with backoff on rate limits, server errors and timeouts before we
+    /// move on to the next one. Returns the answer along with the provider that gave it.
+    async fn call_llm<T: DeserializeOwned>(
+        &self,
+        feed_name: &str,
+        batch: bool,
+        prompt: String,
+    ) -> anyhow::Result<(T, &ProviderKey)> {
         tracing::debug!("Sending prompt to the LLM");
+        let estimated_tokens = estimate_tokens(&prompt);
         let message = ChatMessage::user().content(prompt).build();
         let messages = vec![message];
 
-        let response = self.llm.chat(&messages).await?;
-        let content = response.text().context("No text content in response")?;
-        tracing::trace!(response_content = content);
+        let mut last_error = None;
+        for (index, key) in self.feed_llm(feed_name).providers.iter().enumerate() {
+            let provider = &self.providers[key];
+            if index > 0 {
+                tracing::warn!("Falling back to LLM provider {}", provider.name);
+                self.metrics.record_llm_failover();
+            }
 
-        // Strip markdown JSON code fences if present.
-        let content = content
-            .trim()
-            .strip_prefix("```json")
-            .and_then(|s| s.strip_suffix("```"))
-            .unwrap_or(&content)
-            .to_string();
+            let llm = match batch {
+                true => provider.batch_llm.as_deref().expect("Batching is disabled"),
+                false => provider.llm.as_ref(),
+            };
+            match self
+                .chat_with_retries(feed_name, provider, llm, &messages, estimated_tokens)
+                .await
+            {
+                Ok(content) => {
+                    // Parse the LLM response.
+                    return parse_response(&content)
+                        .map(|response| (response, key))
+                        .inspect_err(|_| self.metrics.record_llm_parse_failure());
+                }
+                // Every provider draws on the same budget.
+                Err(error) if error.is::<BudgetExceeded>() => return Err(error),
+                Err(error) => {
+                    tracing::warn!("LLM provider {} failed: {error}", provider.name);
+                    last_error = Some(error);
+                }
+            }
+        }
+
+        Err(last_error.expect("No LLM providers configured"))
+    }
 
-        // Parse the LLM response.
-        let filter_response: FilterResponse =
-            serde_json::from_str(&content).context("Failed to parse JSON response from LLM")?;
+    /// Sends messages to a provider, retrying transient errors with exponential backoff.
+    ///
+    /// Waits for the provider's rate limits first, and holds back every request to it for as long
+    /// as it asks when it turns us away. Refuses to send anything once a budget has been used up.
+    /// Records the tokens used by the answer against the feed.
+    async fn chat_with_retries(
+        &self,
+        feed_name: &str,
+        provider: &Provider,
+        llm: &dyn LLMProvider,
+        messages: &[ChatMessage],
+        estimated_tokens: u64,
+    ) -> anyhow::Result<String> {
+        let mut attempt = 0;
+        loop {
+            provider.limiter.acquire(estimated_tokens).await;
+            let result = {
+                let _permit = self.request_permits.acquire().await?;
+
+                // Requests queued behind others may find the budget spent by the time they go.
+                if let Some(budget) = self.usage.exceeded_budget() {
+                    return Err(BudgetExceeded(budget).into());
+                }
+                llm.chat(messages).await
+            };
 
-        Ok(filter_response)
+            match result {
+                Ok(response) => {
+                    self.metrics.record_llm_response();
+                    let usage = provider.usage(response.as_ref());
+                    if usage.prompt_tokens + usage.completion_tokens > 0 {
+                        provider.limiter.record_tokens(
+                            estimated_tokens,
+                            usage.prompt_tokens + usage.completion_tokens,
+                        );
+                    }
+                    self.usage.record(feed_name, &provider.name, usage);
+                    let content = response.text().context("No text content in response")?;
+                    tracing::trace!(response_content = content);
+                    return Ok(content);
+                }
+                Err(error)
+                    if is_transient_error(&error) && attempt < self.config.llm.max_retries =>
+                {
+                    let mut backoff = Duration::from_millis(
+                        self.config
+                            .llm
+                            .retry_backoff_ms
+                            .saturating_mul(1 << attempt.min(16)),
+                    );
+                    if let Some(retry_after) = retry_after(&error.to_string()) {
+                        provider.limiter.pause(retry_after);
+                        backoff = backoff.max(retry_after);
+                    }
+                    attempt += 1;
+                    tracing::debug!(
+                        "LLM provider {} failed, retrying in {backoff:?}: {error}",
+                        provider.name
+                    );
+                    self.metrics.record_llm_retry();
+                    tokio::time::sleep(backoff).await;
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
     }
 
     /// Takes an RSS item and a list of filters, and prepares a prompt for the LLM.
     fn prepare_prompt(
         &self,
+        feed_name: &str,
         item: &rss::Item,
-        accept_topics: Vec<String>,
-        reject_topics: Vec<String>,
-    ) -> String {
-        let mut accept_topics = accept_topics.join("; ");
-        let mut reject_topics = reject_topics.join("; ");
+        topics: &Topics,
+    ) -> anyhow::Result<String> {
+        self.feed_llm(feed_name).prompt.render(ItemPrompt {
+            feed: feed_name,
+            accept_topics: &topics.accept,
+            reject_topics: &topics.reject,
+            item: ItemFields::new(item),
+        })
+    }
 
-        // Try to get a summarized content excerpt from the feed.
-        let mut content_excerpt = extract_content_text(item);
+    /// Takes several RSS items and a list of filters, and prepares a single prompt for the LLM.
+    ///
+    /// Items are numbered from 1, for the LLM to refer to them by.
+    fn prepare_batch_prompt(
+        &self,
+        feed_name: &str,
+        items: &[&rss::Item],
+        topics: &Topics,
+    ) -> anyhow::Result<String> {
+        let posts = items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| Post {
+                id: (index + 1).to_string(),
+                item: ItemFields::new(item),
+            })
+            .collect();
 
-        // Always give something to the LLM rather than empty strings.
-        let title = item.title().unwrap_or("none");
-        let description = item.description().unwrap_or("none");
-        if content_excerpt.is_empty() {
-            content_excerpt = "none".to_string();
+        self.feed_llm(feed_name).batch_prompt.render(BatchPrompt {
+            feed: feed_name,
+            accept_topics: &topics.accept,
+            reject_topics: &topics.reject,
+            posts,
+        })
+    }
+}
+
+impl ProviderKey {
+    fn new(provider: &LLMProviderConfig) -> Self {
+        Self {
+            provider: provider.provider.clone(),
+            base_url: provider.base_url.clone(),
+            api_key: provider.api_key.clone(),
+            model: provider.model.clone(),
+        }
+    }
+}
+
+impl Provider {
+    /// Works out the tokens used by an answer, and what they cost.
+    ///
+    /// Some backends don't report usage; their requests are still counted.
+    fn usage(&self, response: &dyn ChatResponse) -> UsageTotals {
+        let (prompt_tokens, completion_tokens) = response
+            .usage()
+            .map(|usage| (usage.prompt_tokens.into(), usage.completion_tokens.into()))
+            .unwrap_or((0, 0));
+        let cost = (prompt_tokens as f64 * self.input_cost_per_million_tokens
+            + completion_tokens as f64 * self.output_cost_per_million_tokens)
+            / 1_000_000.0;
+
+        UsageTotals {
+            requests: 1,
+            prompt_tokens,
+            completion_tokens,
+            cost,
+        }
+    }
+
+    /// Sets up a client for a provider, with a second one for batches when batching is enabled.
+    fn new(provider: &LLMProviderConfig, llm_config: &LLMConfig) -> anyhow::Result<Self> {
+        let name = provider.provider.as_str();
+        // OpenAI-compatible servers are spoken to by our own client.
+        let backend = match name {
+            "anthropic" => Some(LLMBackend::Anthropic),
+            "gemini" => Some(LLMBackend::Google),
+            "openai" => Some(LLMBackend::OpenAI),
+            "ollama" => Some(LLMBackend::Ollama),
+            "openai-compatible" => None,
+            _ => bail!("Invalid LLM provider in configuration"),
         };
-        if accept_topics.is_empty() {
-            accept_topics = "none".to_string();
+
+        // Anthropic only thinks at a temperature of 1, and the client sends 0.7 by default.
+        if name == "anthropic" && llm_config.thinking_enabled && llm_config.temperature != Some(1.0)
+        {
+            bail!("Anthropic thinking needs a temperature of 1 in configuration");
+        }
+
+        // Local servers rarely check API keys, but hosted providers always do.
+        let api_key = match (name, &provider.api_key) {
+            (_, Some(api_key)) => Some(api_key.as_str()),
+            ("ollama", None) => None,
+            // The OpenAI-compatible client won't go without one.
+            ("openai-compatible", None) => Some("none"),
+            (_, None) => bail!("Missing LLM api_key in configuration"),
         };
-        if reject_topics.is_empty() {
-            reject_topics = "none".to_string();
+
+        match &provider.base_url {
+            Some(base_url) => {
+                url::Url::parse(base_url).context("Invalid LLM base_url in configuration")?;
+            }
+            None if name == "openai-compatible" => {
+                bail!("Missing LLM base_url in configuration")
+            }
+            None => (),
+        }
+
+        let build_llm = |schema| -> anyhow::Result<Box<dyn LLMProvider>> {
+            let Some(backend) = backend.clone() else {
+                return Ok(Box::new(OpenAICompatible::new(
+                    provider.base_url.as_deref().unwrap_or_default(),
+                    api_key.unwrap_or_default(),
+                    &provider.model,
+                    llm_config,
+                    schema,
+                )));
+            };
+
+            let mut builder = LLMBuilder::new()
+                .backend(backend)
+                .model(&provider.model)
+                .timeout_seconds(llm_config.timeout_seconds)
+                .reasoning(llm_config.thinking_enabled)
+                .schema(schema);
+            if let Some(system_prompt) = &llm_config.system_prompt {
+                builder = builder.system(system_prompt);
+            }
+            if let Some(temperature) = llm_config.temperature {
+                builder = builder.temperature(temperature);
+            }
+            if let Some(max_tokens) = llm_config.max_tokens {
+                builder = builder.max_tokens(max_tokens);
+            }
+            if let Some(budget) = llm_config.thinking_budget_tokens {
+                builder = builder.reasoning_budget_tokens(budget);
+            }
+            if let Some(effort) = llm_config.reasoning_effort {
+                builder = builder.reasoning_effort(match effort {
+                    ReasoningEffort::Low => llm::chat::ReasoningEffort::Low,
+                    ReasoningEffort::Medium => llm::chat::ReasoningEffort::Medium,
+                    ReasoningEffort::High => llm::chat::ReasoningEffort::High,
+                });
+            }
+            if let Some(api_key) = api_key {
+                builder = builder.api_key(api_key);
+            }
+            if let Some(base_url) = &provider.base_url {
+                builder = builder.base_url(base_url);
+            }
+            builder.build().context("Failed to build LLM client")
         };
 
-        // Hydrate the prompt template.
-        let prompt = self
-            .config
-            .llm
-            .prompt
-            .replace("{title}", title)
-            .replace("{description}", description)
-            .replace("{content_excerpt}", &content_excerpt)
-            .replace("{accept_topics}", &accept_topics)
-            .replace("{reject_topics}", &reject_topics);
+        Ok(Self {
+            name: format!("{name}/{}", provider.model),
+            input_cost_per_million_tokens: provider.input_cost_per_million_tokens,
+            output_cost_per_million_tokens: provider.output_cost_per_million_tokens,
+            limiter: RateLimiter::new(provider.requests_per_minute, provider.tokens_per_minute),
+            limits: (provider.requests_per_minute, provider.tokens_per_minute),
+            settings: format!(
+                "{:?}",
+                (
+                    &llm_config.system_prompt,
+                    llm_config.temperature,
+                    llm_config.max_tokens,
+                    llm_config.thinking_enabled,
+                    llm_config.thinking_budget_tokens,
+                    llm_config.reasoning_effort,
+                )
+            ),
+            llm: build_llm(response_schema())?,
+            batch_llm: match llm_config.batch_size > 1 {
+                true => Some(build_llm(batch_response_schema())?),
+                false => None,
+            },
+        })
+    }
+}
 
-        prompt
+/// Turns the LLM's answer about an item into a decision.
+fn decision_from_response(item: &rss::Item, response: FilterResponse) -> FilterDecision {
+    if response.reject {
+        tracing::info!(
+            "LLM filter rejected '{:?}': {}",
+            item.title(),
+            response.reason.as_deref().unwrap_or("no reason given")
+        );
+    }
+    tracing::debug!("LLM filter decisions: {:?}", response);
+
+    FilterDecision {
+        accepted: response.accept || !response.reject,
+        response: Some(response),
+        decided_at: Utc::now(),
     }
 }
 
-/// Attempts to parse an HTML content section and turn it into plain text.
-fn extract_content_text(item: &rss::Item) -> String {
-    let Some(content) = item.content() else {
-        return String::new();
+/// Whether the LLM refused a request for being larger than its context window.
+fn is_context_length_error(error: &anyhow::Error) -> bool {
+    let Some(error) = error.downcast_ref::<LLMError>() else {
+        return false;
     };
 
-    // Limit the size of the resulting content block.
-    let max_chars: usize = 1000;
+    let message = error.to_string().to_lowercase();
+    [
+        "context length",
+        "context window",
+        "context_length",
+        "too long",
+        "too many tokens",
+    ]
+    .iter()
+    .any(|hint| message.contains(hint))
+}
+
+/// Whether an error is worth retrying: rate limits, server errors, timeouts and lost connections.
+fn is_transient_error(error: &LLMError) -> bool {
+    let message = match error {
+        LLMError::HttpError(message) => message,
+        LLMError::ResponseFormatError { message, .. } => message,
+        _ => return false,
+    };
+
+    match http_status(message) {
+        Some(status) => matches!(status, 408 | 429 | 500..),
+        // Requests that never got an answer.
+        None => matches!(error, LLMError::HttpError(_)),
+    }
+}
+
+/// Finds the HTTP status code in an error message from the LLM client.
+///
+/// Statuses appear as "HTTP status client error (429 Too Many Requests)" or "API returned error
+/// status: 429 Too Many Requests", depending on the backend.
+fn http_status(message: &str) -> Option<u16> {
+    ["error (", "error status: "].iter().find_map(|marker| {
+        let (_, rest) = message.split_once(marker)?;
+        rest.get(..3)?.parse().ok()
+    })
+}
+
+/// The JSON schema we ask the LLM to answer batches with.
+fn batch_response_schema() -> StructuredOutputFormat {
+    let mut decision = response_properties();
+    decision["properties"]["id"] = serde_json::json!({ "type": "string" });
+    decision["required"]
+        .as_array_mut()
+        .unwrap()
+        .insert(0, "id".into());
+
+    StructuredOutputFormat {
+        name: "filter_batch_response".to_owned(),
+        description: Some("Whether each RSS post matches the accept and reject topics".to_owned()),
+        schema: Some(serde_json::json!({
+            "type": "object",
+            "properties": {
+                "decisions": { "type": "array", "items": decision },
+            },
+            "required": ["decisions"],
+            "additionalProperties": false,
+        })),
+        strict: Some(true),
+    }
+}
+
+/// The JSON schema we ask the LLM to answer with, for backends that support structured output.
+fn response_schema() -> StructuredOutputFormat {
+    StructuredOutputFormat {
+        name: "filter_response".to_owned(),
+        description: Some("Whether an RSS post matches the accept and reject topics".to_owned()),
+        schema: Some(response_properties()),
+        strict: Some(true),
+    }
+}
+
+/// The schema of a single item's verdict.
+fn response_properties() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "accept": { "type": "boolean" },
+            "reject": { "type": "boolean" },
+            "reason": { "type": "string" },
+            "topics": { "type": "array", "items": { "type": "string" } },
+        },
+        "required": ["accept", "reject", "reason", "topics"],
+        "additionalProperties": false,
+    })
+}
+
+/// Parses the LLM's answer.
+///
+/// Backends without structured output may wrap the JSON in code fences or prose, so if the
+/// answer isn't JSON by itself we take the first JSON object in it that parses.
+fn parse_response<T: DeserializeOwned>(content: &str) -> anyhow::Result<T> {
+    if let Ok(response) = serde_json::from_str(content.trim()) {
+        return Ok(response);
+    }
+
+    for (start, _) in content.match_indices('{') {
+        let Some(object) = json_object_at(&content[start..]) else {
+            continue;
+        };
+        if let Ok(response) = serde_json::from_str(object) {
+            tracing::debug!("Extracted a JSON object from a noisy LLM response");
+            return Ok(response);
+        }
+    }
+
+    bail!("Failed to parse JSON response from LLM: {content:?}")
+}
+
+/// Returns the balanced `{...}` object at the start of some text, skipping braces in strings.
+fn json_object_at(text: &str) -> Option<&str> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (index, character) in text.char_indices() {
+        if in_string {
+            match character {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+
+        match character {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&text[..=index]);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Escapes text for inclusion in HTML.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Attempts to parse an HTML content section and turn it into plain text, paragraph by paragraph.
+///
+/// Content without paragraphs, such as plain text, is taken as a single one.
+fn content_paragraphs(item: &rss::Item) -> Vec<String> {
+    let Some(content) = item.content() else {
+        return Vec::new();
+    };
 
     let document = scraper::Html::parse_document(content);
     let selector = scraper::Selector::parse("p").unwrap();
-    let mut extracted_text = String::new();
 
     // LLM magic.
-    for element in document.select(&selector) {
-        let text = element.text().collect::<String>().trim().to_string();
-        if !text.is_empty() {
-            let remaining = max_chars.saturating_sub(extracted_text.len());
-            if remaining == 0 {
-                break;
-            }
+    let paragraphs: Vec<String> = document
+        .select(&selector)
+        .map(|element| element.text().collect::<String>().trim().to_string())
+        .filter(|text| !text.is_empty())
+        .collect();
+    if !paragraphs.is_empty() {
+        return paragraphs;
+    }
 
-            if extracted_text.len() + text.len() <= max_chars {
-                if !extracted_text.is_empty() {
-                    extracted_text.push(' ');
-                }
-                extracted_text.push_str(&text);
-            } else {
-                let truncated = &text[..remaining.min(text.len())];
-                if !extracted_text.is_empty() {
-                    extracted_text.push(' ');
-                }
-                extracted_text.push_str(truncated);
-                break;
+    let text = document.root_element().text().collect::<String>();
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.is_empty() {
+        true => Vec::new(),
+        false => vec![text],
+    }
+}
+
+/// Joins paragraphs into an excerpt, cutting the last one short to stay within our size limit.
+fn content_excerpt(paragraphs: &[String]) -> String {
+    let mut extracted_text = String::new();
+
+    for text in paragraphs {
+        let remaining = CONTENT_EXCERPT_BYTES.saturating_sub(extracted_text.len());
+        if remaining == 0 {
+            break;
+        }
+
+        if extracted_text.len() + text.len() <= CONTENT_EXCERPT_BYTES {
+            if !extracted_text.is_empty() {
+                extracted_text.push(' ');
             }
+            extracted_text.push_str(text);
+        } else {
+            // Never cut a character in half.
+            let mut end = remaining.min(text.len());
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            if !extracted_text.is_empty() {
+                extracted_text.push(' ');
+            }
+            extracted_text.push_str(&text[..end]);
+            break;
         }
     }
 
@@ -201,16 +1110,137 @@ mod tests {
         rss::Channel::read_from(feed_xml.as_bytes()).expect("Failed to parse RSS feed")
     }
 
+    #[test]
+    fn test_thinking_config() {
+        let sample = include_str!("../config/sample.toml");
+        let filter = |config: &str| {
+            let directory = tempfile::tempdir().unwrap();
+            super::LLMFilter::new(
+                toml::from_str(config).unwrap(),
+                super::DecisionCache::new(directory.path().join("cache.json"), 60, 2),
+                crate::metrics::Metrics::default(),
+                crate::usage::UsageTracker::new(directory.path().join("usage.json"), None, None),
+            )
+        };
+
+        // Provider defaults leave no room to think.
+        let thinking = sample.replace("thinking_enabled = false", "thinking_enabled = true");
+        assert!(filter(&thinking).is_err());
+
+        let budgeted = thinking
+            .replace("# max_tokens = 1024", "max_tokens = 4096")
+            .replace(
+                "# thinking_budget_tokens = 2048",
+                "thinking_budget_tokens = 2048",
+            );
+        assert!(filter(&budgeted).is_ok());
+
+        let anthropic = budgeted.replacen(r#"provider = "openai""#, r#"provider = "anthropic""#, 1);
+        assert!(filter(&anthropic).is_err());
+        let anthropic = anthropic.replace("# temperature = 0.2", "temperature = 1.0");
+        assert!(filter(&anthropic).is_ok());
+    }
+
+    #[test]
+    fn test_annotate() {
+        use super::{Annotation, FilterDecision};
+
+        let decision = FilterDecision {
+            accepted: true,
+            response: serde_json::from_str(
+                r#"{"accept": true, "reject": false, "reason": "About <Rust>", "topics": ["rust"]}"#,
+            )
+            .unwrap(),
+            decided_at: chrono::Utc::now(),
+        };
+
+        let mut item = rss::Item {
+            description: Some("<p>Post</p>".to_owned()),
+            ..Default::default()
+        };
+        decision.annotate(
+            &mut item,
+            &[Annotation::Description, Annotation::Categories],
+        );
+
+        assert_eq!(
+            item.description(),
+            Some("<p>Post</p><p>Filter: About &lt;Rust&gt; (topics: rust)</p>")
+        );
+        assert_eq!(item.categories()[0].name(), "rust");
+    }
+
+    #[test]
+    fn test_is_transient_error() {
+        use super::is_transient_error;
+        use llm::error::LLMError;
+
+        let http = |message: &str| LLMError::HttpError(message.to_owned());
+        let status = |message: &str| LLMError::ResponseFormatError {
+            message: message.to_owned(),
+            raw_response: String::new(),
+        };
+
+        assert!(is_transient_error(&http(
+            "HTTP status client error (429 Too Many Requests) for url (http://localhost/api/chat)"
+        )));
+        assert!(is_transient_error(&status(
+            "OpenAI-compatible API returned error status: 503 Service Unavailable"
+        )));
+        assert!(is_transient_error(&http(
+            "error sending request for url (http://localhost/api/chat)"
+        )));
+        assert!(!is_transient_error(&http(
+            "HTTP status client error (401 Unauthorized) for url (http://localhost/api/chat)"
+        )));
+        assert!(!is_transient_error(&status(
+            "OpenAI-compatible API returned error status: 400 Bad Request"
+        )));
+        assert!(!is_transient_error(&LLMError::AuthError(
+            "Missing API key".to_owned()
+        )));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        use super::{BatchResponse, FilterResponse, parse_response};
+
+        let plain = r#"{"accept": true, "reject": false}"#;
+        assert!(parse_response::<FilterResponse>(plain).unwrap().accept);
+
+        let fenced =
+            "```json\n{\"accept\": false, \"reject\": true, \"reason\": \"Ads {sponsored}\"}\n```";
+        let response: FilterResponse = parse_response(fenced).unwrap();
+        assert!(response.reject);
+        assert_eq!(response.reason.as_deref(), Some("Ads {sponsored}"));
+
+        let noisy = r#"Sure! Considering {the topics}, here it is: {"accept": true, "reject": true} Hope that helps."#;
+        assert!(parse_response::<FilterResponse>(noisy).unwrap().accept);
+
+        assert!(parse_response::<FilterResponse>("I cannot decide.").is_err());
+
+        let batch = r#"{"decisions": [{"id": "2", "accept": false, "reject": true, "reason": "Ads", "topics": []}]}"#;
+        let response: BatchResponse = parse_response(batch).unwrap();
+        assert_eq!(response.decisions[0].id, "2");
+        assert!(response.decisions[0].response.reject);
+    }
+
     #[test]
     fn test_extract_content_text() {
         let channel = test_feed_channel();
         let item = channel.items().first().expect("No items in feed");
 
-        // Test the extract_content_text function
-        let extracted_text = super::extract_content_text(item);
+        // Test content extraction
+        let extracted_text = super::content_excerpt(&super::content_paragraphs(item));
 
         let expected_text = r#"Steven Byrnes is a physicist/AI researcher/amateur neuroscientist; needless to say, he blogs on Less Wrong. I finally got around to reading his 2024 series giving a predictive processing perspective on intuitive self-models. If that sounds boring, it shouldn’t: Byrnes charges head-on into some of the toughest subjects in psychology, including trance, amnesia, and multiple personalities. I found his perspective enlightening (no pun intended; meditation is another one of his topics) and thought I would share. It all centers around this picture: But first: some excruciatingly obvious philosophical preliminaries. We don’t directly perceive the external world. Every philosopher has their own way of saying exactly what it is we do perceive, but the predictive processing interpretation is that we perceive our models of the world. To be very naive and hand-wavey, lower-level brain centers get sense-data, make a guess about what produced that sense data, then “show” “us” that guess. "#;
 
-        assert_eq!(extracted_text, expected_text)
+        assert_eq!(extracted_text, expected_text);
+
+        // Excerpts are cut between characters, never inside one.
+        let paragraphs = vec!["é".repeat(600)];
+        assert_eq!(super::content_excerpt(&paragraphs), "é".repeat(500));
+        let paragraphs = vec!["ab".to_owned(), "’".repeat(400)];
+        assert_eq!(super::content_excerpt(&paragraphs).len(), 999);
     }
 }
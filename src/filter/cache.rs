@@ -0,0 +1,191 @@
+//! A persistent cache of the LLM's answers, so the same item is never paid for twice.
+
+use super::FilterResponse;
+use crate::storage::{read_json_file, write_atomically};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+#[derive(Clone, Deserialize, Serialize)]
+struct CachedResponse {
+    response: FilterResponse,
+    cached_at: DateTime<Utc>,
+}
+
+struct Inner {
+    entries: HashMap<String, CachedResponse>,
+
+    /// How long an answer stays valid.
+    ttl: TimeDelta,
+
+    /// How many answers to keep; the oldest go first. Zero disables the cache.
+    max_entries: usize,
+
+    /// A location to store and load the cache.
+    file: PathBuf,
+}
+
+/// Shared cache of LLM answers, keyed by a hash of everything that went into the question.
+#[derive(Clone)]
+pub struct DecisionCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl DecisionCache {
+    pub fn new(file: PathBuf, ttl_seconds: u64, max_entries: usize) -> Self {
+        // TTLs too long to represent never expire.
+        let ttl = i64::try_from(ttl_seconds)
+            .ok()
+            .and_then(TimeDelta::try_seconds)
+            .unwrap_or(TimeDelta::MAX);
+
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                entries: HashMap::new(),
+                ttl,
+                max_entries,
+                file,
+            })),
+        }
+    }
+
+    /// Hashes the parts of a question into a cache key.
+    pub fn key(parts: &[&str]) -> String {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            // Prefix each part with its length, so that parts can't run into each other.
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+
+        hasher
+            .finalize()
+            .iter()
+            .fold(String::with_capacity(64), |mut key, byte| {
+                let _ = write!(key, "{byte:02x}");
+                key
+            })
+    }
+
+    /// Returns the answer cached for a question, if it hasn't expired.
+    pub fn get(&self, key: &str) -> Option<FilterResponse> {
+        let inner = self.lock();
+        let cached = inner.entries.get(key)?;
+        (Utc::now() - cached.cached_at < inner.ttl).then(|| cached.response.clone())
+    }
+
+    /// Caches the answer to a question, making room for it if needed.
+    pub fn insert(&self, key: String, response: FilterResponse) {
+        let mut inner = self.lock();
+        if inner.max_entries == 0 {
+            return;
+        }
+
+        let cached = CachedResponse {
+            response,
+            cached_at: Utc::now(),
+        };
+        inner.entries.insert(key, cached);
+        inner.prune();
+    }
+
+    /// Writes the cache to a file.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let inner = self.lock();
+        if inner.max_entries == 0 {
+            return Ok(());
+        }
+
+        tracing::debug!("Saving decision cache to file");
+        let json = serde_json::to_string(&inner.entries)?;
+        write_atomically(&inner.file, json.as_bytes(), false)?;
+        Ok(())
+    }
+
+    /// Loads the cache from a file, dropping anything that has since expired.
+    pub fn load(&self) -> anyhow::Result<()> {
+        let mut inner = self.lock();
+        if inner.max_entries == 0 {
+            return Ok(());
+        }
+
+        tracing::debug!("Loading decision cache from file");
+        if let Some(entries) = read_json_file(&inner.file)? {
+            inner.entries = entries;
+            inner.prune();
+            tracing::info!("Loaded {} cached decisions from file", inner.entries.len());
+        }
+
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Inner {
+    /// Drops expired answers, then the oldest ones until we're within our size limit.
+    fn prune(&mut self) {
+        let now = Utc::now();
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, cached| now - cached.cached_at < ttl);
+
+        if self.entries.len() > self.max_entries {
+            let mut ages: Vec<_> = self
+                .entries
+                .iter()
+                .map(|(key, cached)| (cached.cached_at, key.clone()))
+                .collect();
+            ages.sort_unstable();
+
+            let excess = self.entries.len() - self.max_entries;
+            for (_, key) in ages.into_iter().take(excess) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecisionCache, FilterResponse};
+
+    #[test]
+    fn test_decision_cache() {
+        let response = |accept| FilterResponse {
+            accept,
+            reject: !accept,
+            reason: None,
+            topics: Vec::new(),
+        };
+
+        assert_ne!(
+            DecisionCache::key(&["ab", "c"]),
+            DecisionCache::key(&["a", "bc"])
+        );
+
+        let directory = tempfile::tempdir().unwrap();
+        let cache = DecisionCache::new(directory.path().join("cache.json"), 60, 2);
+        for key in ["a", "b", "c"] {
+            cache.insert(key.to_owned(), response(true));
+        }
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("c").unwrap().accept);
+
+        // Nothing outlives a zero TTL.
+        let cache = DecisionCache::new(directory.path().join("cache.json"), 0, 2);
+        cache.insert("a".to_owned(), response(false));
+        assert!(cache.get("a").is_none());
+
+        // A TTL too long to represent keeps answers for good, rather than panicking.
+        let cache = DecisionCache::new(directory.path().join("cache.json"), u64::MAX, 2);
+        cache.insert("a".to_owned(), response(true));
+        assert!(cache.get("a").unwrap().accept);
+    }
+}
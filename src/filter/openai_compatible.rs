@@ -0,0 +1,108 @@
+//! A client for servers that speak OpenAI's chat completions API: llama.cpp, vLLM, LM Studio...
+//!
+//! The `llm` crate's OpenAI backend talks to the newer Responses API, which local servers rarely
+//! implement, so we point its generic chat completions client at the server instead.
+
+use crate::config::LLMConfig;
+use llm::{
+    LLMProvider, async_trait,
+    chat::{ChatMessage, ChatProvider, ChatResponse, StructuredOutputFormat, Tool},
+    completion::{CompletionProvider, CompletionRequest, CompletionResponse},
+    embedding::EmbeddingProvider,
+    error::LLMError,
+    models::ModelsProvider,
+    providers::openai_compatible::{OpenAICompatibleProvider, OpenAIProviderConfig},
+    stt::SpeechToTextProvider,
+    tts::TextToSpeechProvider,
+};
+
+pub struct OpenAICompatibleConfig;
+
+impl OpenAIProviderConfig for OpenAICompatibleConfig {
+    const PROVIDER_NAME: &'static str = "OpenAI-compatible";
+    const DEFAULT_BASE_URL: &'static str = "http://localhost:8000/v1/";
+    const DEFAULT_MODEL: &'static str = "default";
+    const SUPPORTS_STRUCTURED_OUTPUT: bool = true;
+    const SUPPORTS_REASONING_EFFORT: bool = true;
+}
+
+pub struct OpenAICompatible(OpenAICompatibleProvider<OpenAICompatibleConfig>);
+
+impl OpenAICompatible {
+    /// Sets up a client, with the model parameters from our `[llm]` configuration.
+    pub fn new(
+        base_url: &str,
+        api_key: &str,
+        model: &str,
+        config: &LLMConfig,
+        json_schema: StructuredOutputFormat,
+    ) -> Self {
+        Self(OpenAICompatibleProvider::new(
+            api_key,
+            Some(base_url.to_owned()),
+            Some(model.to_owned()),
+            config.max_tokens,
+            config.temperature,
+            Some(config.timeout_seconds),
+            config.system_prompt.clone(),
+            None,
+            None,
+            None,
+            None,
+            config
+                .reasoning_effort
+                .map(|effort| effort.as_str().to_owned()),
+            Some(json_schema),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ))
+    }
+}
+
+impl LLMProvider for OpenAICompatible {}
+
+#[async_trait]
+impl ChatProvider for OpenAICompatible {
+    async fn chat_with_tools(
+        &self,
+        messages: &[ChatMessage],
+        tools: Option<&[Tool]>,
+    ) -> Result<Box<dyn ChatResponse>, LLMError> {
+        self.0.chat_with_tools(messages, tools).await
+    }
+}
+
+// We only ever chat.
+
+#[async_trait]
+impl CompletionProvider for OpenAICompatible {
+    async fn complete(&self, _: &CompletionRequest) -> Result<CompletionResponse, LLMError> {
+        Err(LLMError::ProviderError("Completion not supported".into()))
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAICompatible {
+    async fn embed(&self, _: Vec<String>) -> Result<Vec<Vec<f32>>, LLMError> {
+        Err(LLMError::ProviderError("Embedding not supported".into()))
+    }
+}
+
+#[async_trait]
+impl SpeechToTextProvider for OpenAICompatible {
+    async fn transcribe(&self, _: Vec<u8>) -> Result<String, LLMError> {
+        Err(LLMError::ProviderError(
+            "Speech to text not supported".into(),
+        ))
+    }
+}
+
+#[async_trait]
+impl TextToSpeechProvider for OpenAICompatible {}
+
+#[async_trait]
+impl ModelsProvider for OpenAICompatible {}
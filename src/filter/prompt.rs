@@ -0,0 +1,332 @@
+//! Prompt templates, rendered with MiniJinja.
+//!
+//! Templates written for the old `{placeholder}` syntax, without any Jinja tags, still work: their
+//! placeholders are translated, with missing values reading "none" as they used to.
+
+use super::{content_excerpt, content_paragraphs};
+use anyhow::{Context, bail};
+use minijinja::{Environment, UndefinedBehavior};
+use serde::Serialize;
+
+/// The variables a prompt for a single item can use.
+const ITEM_VARIABLES: &[&str] = &[
+    "feed",
+    "accept_topics",
+    "reject_topics",
+    "title",
+    "link",
+    "author",
+    "categories",
+    "pub_date",
+    "enclosure_type",
+    "description",
+    "content",
+    "content_excerpt",
+];
+
+/// The variables a prompt for a batch of items can use; each of the `posts` has an `id` and the
+/// item variables above.
+const BATCH_VARIABLES: &[&str] = &["feed", "accept_topics", "reject_topics", "posts"];
+
+/// What the old `{posts}` placeholder stood for.
+const LEGACY_POSTS: &str = r#"{% for post in posts %}{{ "\n\n" if not loop.first else "" }}Post id: {{ post.id }}
+Post title: {{ post.title or "none" }}
+Post description: {{ post.description or "none" }}
+Post content excerpt: {{ post.content_excerpt or "none" }}{% endfor %}"#;
+
+/// A prompt template, checked for mistakes when it's set up.
+pub struct PromptTemplate {
+    env: Environment<'static>,
+
+    /// The template as configured.
+    source: String,
+
+    /// Whether the prompt says which feed an item is from, so the same item can't share an
+    /// answer across feeds.
+    uses_feed: bool,
+}
+
+impl PromptTemplate {
+    /// Sets up a template for prompts about a single item.
+    pub fn item(source: &str) -> anyhow::Result<Self> {
+        Self::new(source, ITEM_VARIABLES)
+    }
+
+    /// Sets up a template for prompts about a batch of items.
+    pub fn batch(source: &str) -> anyhow::Result<Self> {
+        Self::new(source, BATCH_VARIABLES)
+    }
+
+    fn new(source: &str, variables: &[&str]) -> anyhow::Result<Self> {
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        env.add_template_owned("prompt", translate_legacy(source))?;
+
+        let used = env.get_template("prompt")?.undeclared_variables(false);
+        let mut unknown: Vec<&str> = used
+            .iter()
+            .map(String::as_str)
+            .filter(|name| !variables.contains(name))
+            .filter(|name| env.globals().all(|(global, _)| global != *name))
+            .collect();
+        if !unknown.is_empty() {
+            unknown.sort_unstable();
+            bail!(
+                "Unknown placeholders {}, expected some of {}",
+                unknown.join(", "),
+                variables.join(", ")
+            );
+        }
+
+        Ok(Self {
+            uses_feed: used.contains("feed"),
+            env,
+            source: source.to_owned(),
+        })
+    }
+
+    /// Fills in the template.
+    pub fn render(&self, context: impl Serialize) -> anyhow::Result<String> {
+        self.env
+            .get_template("prompt")?
+            .render(context)
+            .context("Failed to render LLM prompt")
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn uses_feed(&self) -> bool {
+        self.uses_feed
+    }
+}
+
+/// What a prompt can say about an item. Anything the item doesn't have is left empty.
+#[derive(Serialize)]
+pub struct ItemFields<'a> {
+    title: Option<&'a str>,
+    link: Option<&'a str>,
+    author: Option<&'a str>,
+    categories: Vec<&'a str>,
+    pub_date: Option<&'a str>,
+    enclosure_type: Option<&'a str>,
+    description: Option<&'a str>,
+
+    /// The item's content as plain text, in full and as a short excerpt.
+    content: Option<String>,
+    content_excerpt: Option<String>,
+}
+
+impl<'a> ItemFields<'a> {
+    pub fn new(item: &'a rss::Item) -> Self {
+        let present = |text: &&str| !text.trim().is_empty();
+        let paragraphs = content_paragraphs(item);
+        let (content, content_excerpt) = match paragraphs.is_empty() {
+            true => (None, None),
+            false => (
+                Some(paragraphs.join(" ")),
+                Some(content_excerpt(&paragraphs)),
+            ),
+        };
+
+        Self {
+            title: item.title().filter(present),
+            link: item.link().filter(present),
+            author: item
+                .author()
+                .or_else(|| {
+                    let creators = item.dublin_core_ext()?.creators();
+                    creators.first().map(String::as_str)
+                })
+                .filter(present),
+            categories: item
+                .categories()
+                .iter()
+                .map(|category| category.name())
+                .collect(),
+            pub_date: item.pub_date().filter(present),
+            enclosure_type: item
+                .enclosure()
+                .map(|enclosure| enclosure.mime_type())
+                .filter(present),
+            description: item.description().filter(present),
+            content,
+            content_excerpt,
+        }
+    }
+}
+
+/// Everything a prompt about a single item can use.
+#[derive(Serialize)]
+pub struct ItemPrompt<'a> {
+    pub feed: &'a str,
+    pub accept_topics: &'a [String],
+    pub reject_topics: &'a [String],
+    #[serde(flatten)]
+    pub item: ItemFields<'a>,
+}
+
+/// Everything a prompt about a batch of items can use.
+#[derive(Serialize)]
+pub struct BatchPrompt<'a> {
+    pub feed: &'a str,
+    pub accept_topics: &'a [String],
+    pub reject_topics: &'a [String],
+    pub posts: Vec<Post<'a>>,
+}
+
+/// An item in a batch, with the id the LLM should answer about it with.
+#[derive(Serialize)]
+pub struct Post<'a> {
+    pub id: String,
+    #[serde(flatten)]
+    pub item: ItemFields<'a>,
+}
+
+/// Translates a template written with `{placeholder}`s into Jinja syntax.
+///
+/// Templates with any Jinja tags are taken to be Jinja already, and left alone.
+fn translate_legacy(source: &str) -> String {
+    if ["{{", "{%", "{#"].iter().any(|tag| source.contains(tag)) {
+        return source.to_owned();
+    }
+
+    let mut translated = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find('{') {
+        translated.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let placeholder = rest.split_once('}').map(|(name, _)| name).filter(|name| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|character| character.is_ascii_alphanumeric() || character == '_')
+        });
+        match placeholder {
+            Some(name) => {
+                translated.push_str(&match name {
+                    "posts" => LEGACY_POSTS.to_owned(),
+                    "accept_topics" | "reject_topics" => {
+                        format!(r#"{{{{ {name} | join("; ") or "none" }}}}"#)
+                    }
+                    _ => format!(r#"{{{{ {name} or "none" }}}}"#),
+                });
+                rest = &rest[name.len() + 1..];
+            }
+            None => translated.push('{'),
+        }
+    }
+    translated.push_str(rest);
+
+    translated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatchPrompt, ItemFields, ItemPrompt, Post, PromptTemplate};
+
+    #[test]
+    fn test_prompt_template() {
+        let item = rss::Item {
+            title: Some("Rust 2024".to_owned()),
+            link: Some("https://example.com/rust".to_owned()),
+            categories: vec![rss::Category {
+                name: "programming".to_owned(),
+                domain: None,
+            }],
+            ..Default::default()
+        };
+        let accept = vec!["rust".to_owned(), "web".to_owned()];
+        let context = || ItemPrompt {
+            feed: "news",
+            accept_topics: &accept,
+            reject_topics: &[],
+            item: ItemFields::new(&item),
+        };
+
+        // Old templates read as they used to, JSON examples and all.
+        let legacy = PromptTemplate::item(
+            r#"{title} ({description}) accept {accept_topics} reject {reject_topics} {"accept": true}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            legacy.render(context()).unwrap(),
+            r#"Rust 2024 (none) accept rust; web reject none {"accept": true}"#
+        );
+
+        let jinja = PromptTemplate::item(
+            "{{ title }}{% if author %} by {{ author }}{% endif %} <{{ link }}> [{{ categories | join(', ') }}]",
+        )
+        .unwrap();
+        assert_eq!(
+            jinja.render(context()).unwrap(),
+            "Rust 2024 <https://example.com/rust> [programming]"
+        );
+        assert!(!jinja.uses_feed());
+
+        // Mistakes are caught when the template is set up.
+        let error = PromptTemplate::item("{title} {summary}").err().unwrap();
+        assert!(error.to_string().contains("summary"));
+        assert!(PromptTemplate::item("{% if title %}").is_err());
+        assert!(PromptTemplate::batch("{{ title }}").is_err());
+
+        let batch = PromptTemplate::batch("{posts}").unwrap();
+        let posts = ["1", "2"]
+            .map(|id| Post {
+                id: id.to_owned(),
+                item: ItemFields::new(&item),
+            })
+            .into();
+        let prompt = batch
+            .render(BatchPrompt {
+                feed: "news",
+                accept_topics: &accept,
+                reject_topics: &[],
+                posts,
+            })
+            .unwrap();
+        assert!(prompt.starts_with("Post id: 1\nPost title: Rust 2024\n"));
+        assert!(prompt.contains("excerpt: none\n\nPost id: 2\n"));
+    }
+
+    #[test]
+    fn test_plain_text_content() {
+        // JSON Feed items may only carry plain text, without any paragraphs.
+        let feed = r#"{
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": "Notes",
+            "items": [{"id": "1", "content_text": "Hello,\n\nplain   world."}]
+        }"#;
+        let channel = crate::jsonfeed::read_channel(feed).unwrap();
+        let item = &channel.items()[0];
+
+        let template = PromptTemplate::item("{{ content }} / {{ content_excerpt }}").unwrap();
+        let prompt = template
+            .render(ItemPrompt {
+                feed: "notes",
+                accept_topics: &[],
+                reject_topics: &[],
+                item: ItemFields::new(item),
+            })
+            .unwrap();
+        assert_eq!(prompt, "Hello, plain world. / Hello, plain world.");
+    }
+
+    #[test]
+    fn test_sample_prompts() {
+        let config: crate::config::Config =
+            toml::from_str(include_str!("../../config/sample.toml")).unwrap();
+
+        PromptTemplate::item(&config.llm.prompt).unwrap();
+        PromptTemplate::batch(&config.llm.batch_prompt).unwrap();
+        for feed in config.feeds.values() {
+            if let Some(prompt) = feed.llm.as_ref().and_then(|llm| llm.prompt.as_ref()) {
+                PromptTemplate::item(prompt).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Client-side rate limiting, so bursts of new items queue up rather than run into 429s.
+
+use std::num::NonZeroU32;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A token bucket, refilled continuously up to its capacity.
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn per_minute(limit: NonZeroU32, now: Instant) -> Self {
+        let limit = f64::from(limit.get());
+        Self {
+            capacity: limit,
+            available: limit,
+            per_second: limit / 60.0,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// How long until `amount` is available. Never more than a full bucket is asked for.
+    fn wait_for(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.available;
+        match missing > 0.0 {
+            true => Duration::from_secs_f64(missing / self.per_second),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+struct State {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+
+    /// Set when the provider asks us to hold off for a while.
+    paused_until: Option<Instant>,
+}
+
+/// Limits how many requests, and how many tokens, are sent to a provider each minute.
+///
+/// Callers wait their turn, in order.
+pub struct RateLimiter {
+    state: Mutex<State>,
+
+    /// Held by the caller at the front of the queue while it waits.
+    turn: tokio::sync::Mutex<()>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        requests_per_minute: Option<NonZeroU32>,
+        tokens_per_minute: Option<NonZeroU32>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            state: Mutex::new(State {
+                requests: requests_per_minute.map(|limit| Bucket::per_minute(limit, now)),
+                tokens: tokens_per_minute.map(|limit| Bucket::per_minute(limit, now)),
+                paused_until: None,
+            }),
+            turn: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Waits until a request of about `tokens` tokens can be sent, and takes it from the limits.
+    pub async fn acquire(&self, tokens: u64) {
+        let _turn = self.turn.lock().await;
+
+        loop {
+            let wait = {
+                let mut state = self.lock();
+                let now = Instant::now();
+                state.refill(now);
+
+                let mut wait = state
+                    .paused_until
+                    .map(|until| until.saturating_duration_since(now))
+                    .unwrap_or_default();
+                if let Some(requests) = &state.requests {
+                    wait = wait.max(requests.wait_for(1.0));
+                }
+                if let Some(bucket) = &state.tokens {
+                    wait = wait.max(bucket.wait_for(tokens as f64));
+                }
+
+                if wait.is_zero() {
+                    if let Some(requests) = &mut state.requests {
+                        requests.available -= 1.0;
+                    }
+                    if let Some(bucket) = &mut state.tokens {
+                        bucket.available -= tokens as f64;
+                    }
+                    return;
+                }
+                wait
+            };
+
+            tracing::debug!("Waiting {wait:?} for the LLM rate limit");
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Corrects the tokens taken for a request once we know how many it really used.
+    pub fn record_tokens(&self, estimated: u64, actual: u64) {
+        if let Some(bucket) = &mut self.lock().tokens {
+            bucket.available += estimated as f64 - actual as f64;
+        }
+    }
+
+    /// Holds every request back for a while, as the provider asked.
+    pub fn pause(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut state = self.lock();
+        if state
+            .paused_until
+            .is_none_or(|paused_until| paused_until < until)
+        {
+            state.paused_until = Some(until);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl State {
+    fn refill(&mut self, now: Instant) {
+        for bucket in [&mut self.requests, &mut self.tokens].into_iter().flatten() {
+            bucket.refill(now);
+        }
+        if self.paused_until.is_some_and(|until| until <= now) {
+            self.paused_until = None;
+        }
+    }
+}
+
+/// Estimates how many tokens a prompt will use, at about four characters per token.
+pub fn estimate_tokens(prompt: &str) -> u64 {
+    (prompt.len() as u64).div_ceil(4)
+}
+
+/// Finds how long the provider asked us to wait before trying again, in an error message.
+///
+/// Our LLM client doesn't expose response headers, so this looks for a `Retry-After` value
+/// repeated in the body, OpenAI's "try again in 1.5s" and Gemini's `"retryDelay": "30s"`.
+pub fn retry_after(message: &str) -> Option<Duration> {
+    let lowercase = message.to_lowercase();
+    [
+        "retry-after:",
+        "retry after",
+        "try again in",
+        "\"retrydelay\":",
+    ]
+    .iter()
+    .find_map(|marker| {
+        let (_, rest) = lowercase.split_once(marker)?;
+        let rest = rest.trim_start_matches([' ', '"']);
+        let end = rest
+            .find(|character: char| !character.is_ascii_digit() && character != '.')
+            .unwrap_or(rest.len());
+        let value: f64 = rest[..end].parse().ok()?;
+
+        let unit = rest[end..].trim_start();
+        let seconds = if unit.starts_with("ms") {
+            value / 1000.0
+        } else if unit.starts_with('m') {
+            value * 60.0
+        } else {
+            value
+        };
+        Duration::try_from_secs_f64(seconds).ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bucket, retry_after};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::per_minute(60.try_into().unwrap(), start);
+        bucket.available = 0.0;
+        assert_eq!(bucket.wait_for(1.0), Duration::from_secs(1));
+
+        bucket.refill(start + Duration::from_secs(30));
+        assert_eq!(bucket.available, 30.0);
+        assert_eq!(bucket.wait_for(1.0), Duration::ZERO);
+
+        // Requests larger than the bucket wait for a full one rather than forever.
+        bucket.refill(start + Duration::from_secs(600));
+        assert_eq!(bucket.available, 60.0);
+        assert_eq!(bucket.wait_for(1000.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(
+            retry_after("Rate limit reached. Please try again in 1.5s."),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            retry_after(r#"{"error": {"details": [{"retryDelay": "30s"}]}}"#),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after("Please try again in 250ms"),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(retry_after("Too Many Requests"), None);
+    }
+}
@@ -0,0 +1,78 @@
+//! Feed health tracking.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// A feed is reported as unhealthy after this many consecutive failed polls.
+const UNHEALTHY_AFTER_FAILURES: u32 = 3;
+
+/// How a feed has been faring, as seen by the poller.
+#[derive(Clone, Debug, Serialize)]
+pub struct FeedHealth {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub next_poll: Option<DateTime<Utc>>,
+}
+
+impl Default for FeedHealth {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            consecutive_failures: 0,
+            last_success: None,
+            last_failure: None,
+            last_error: None,
+            next_poll: None,
+        }
+    }
+}
+
+/// Shared record of every feed's health.
+#[derive(Clone, Default)]
+pub struct HealthTracker {
+    inner: Arc<RwLock<HashMap<String, FeedHealth>>>,
+}
+
+impl HealthTracker {
+    /// Records a successful poll of a feed.
+    pub async fn record_success(&self, feed_name: &str) {
+        let mut feeds = self.inner.write().await;
+        let health = feeds.entry(feed_name.to_owned()).or_default();
+
+        health.healthy = true;
+        health.consecutive_failures = 0;
+        health.last_success = Some(Utc::now());
+    }
+
+    /// Records a failed poll of a feed.
+    ///
+    /// Returns how many times in a row the feed has now failed.
+    pub async fn record_failure(&self, feed_name: &str, error: &anyhow::Error) -> u32 {
+        let mut feeds = self.inner.write().await;
+        let health = feeds.entry(feed_name.to_owned()).or_default();
+
+        health.consecutive_failures += 1;
+        health.healthy = health.consecutive_failures < UNHEALTHY_AFTER_FAILURES;
+        health.last_failure = Some(Utc::now());
+        health.last_error = Some(format!("{error:#}"));
+
+        health.consecutive_failures
+    }
+
+    /// Records when a feed will next be polled.
+    pub async fn record_next_poll(&self, feed_name: &str, next_poll: DateTime<Utc>) {
+        let mut feeds = self.inner.write().await;
+        feeds.entry(feed_name.to_owned()).or_default().next_poll = Some(next_poll);
+    }
+
+    /// Returns a copy of every feed's health.
+    pub async fn snapshot(&self) -> HashMap<String, FeedHealth> {
+        self.inner.read().await.clone()
+    }
+}
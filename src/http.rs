@@ -0,0 +1,221 @@
+//! HTTP client for retrieving feeds.
+
+use crate::config::{FeedAuth, HttpConfig};
+use anyhow::{Context, bail};
+use base64::Engine;
+use reqwest::{
+    Response, StatusCode, Url,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+};
+use std::time::Duration;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// An HTTP client along with the credentials it sends and the limits it enforces on responses.
+#[derive(Clone)]
+pub struct FeedClient {
+    client: reqwest::Client,
+    auth_headers: HeaderMap,
+    max_redirects: usize,
+    max_response_bytes: u64,
+}
+
+impl FeedClient {
+    /// Builds a client from HTTP settings, with credentials to send to the feed's origin.
+    pub fn new(config: &HttpConfig, auth: Option<&FeedAuth>) -> anyhow::Result<Self> {
+        let compression = config.compression.unwrap_or(true);
+        let seconds_or =
+            |seconds: Option<u64>, default| seconds.map_or(default, Duration::from_secs);
+
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(seconds_or(
+                config.connect_timeout_seconds,
+                DEFAULT_CONNECT_TIMEOUT,
+            ))
+            .read_timeout(seconds_or(
+                config.read_timeout_seconds,
+                DEFAULT_READ_TIMEOUT,
+            ))
+            .timeout(seconds_or(config.timeout_seconds, DEFAULT_TIMEOUT))
+            .user_agent(config.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+            .gzip(compression)
+            .brotli(compression)
+            // Redirects are followed in `get`, which decides where credentials may go.
+            .redirect(reqwest::redirect::Policy::none());
+
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy).context("Invalid proxy URL")?);
+        }
+
+        Ok(Self {
+            client: builder.build().context("Failed to build HTTP client")?,
+            auth_headers: match auth {
+                Some(auth) => auth_headers(auth)?,
+                None => HeaderMap::new(),
+            },
+            max_redirects: config.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS),
+            max_response_bytes: config
+                .max_response_bytes
+                .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+        })
+    }
+
+    /// Sends a GET request with the given headers, following redirects.
+    ///
+    /// Credentials are only sent to the origin of the original URL, so that a redirect to
+    /// another host (or from HTTPS to HTTP) can't leak them.
+    pub async fn get(&self, url: &str, headers: HeaderMap) -> anyhow::Result<Response> {
+        let mut url = Url::parse(url).context("Invalid feed URL")?;
+        let origin = url.origin();
+
+        for _ in 0..=self.max_redirects {
+            let mut request = self.client.get(url.clone()).headers(headers.clone());
+            if url.origin() == origin {
+                request = request.headers(self.auth_headers.clone());
+            }
+            let response = request.send().await.context("Failed to HTTP GET feed")?;
+
+            let location = match response.status() {
+                StatusCode::MOVED_PERMANENTLY
+                | StatusCode::FOUND
+                | StatusCode::SEE_OTHER
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT => response.headers().get(header::LOCATION),
+                _ => None,
+            };
+            let Some(location) = location else {
+                return Ok(response);
+            };
+
+            let location = location.to_str().context("Invalid redirect location")?;
+            url = url.join(location).context("Invalid redirect location")?;
+            tracing::debug!("Following redirect to {url}");
+        }
+
+        bail!("Too many redirects")
+    }
+
+    /// Reads a response's body as text, refusing bodies larger than our limit.
+    ///
+    /// Decodes the body with the charset given in its content type, or UTF-8 otherwise.
+    pub async fn read_text(&self, mut response: Response) -> anyhow::Result<String> {
+        if let Some(length) = response.content_length()
+            && length > self.max_response_bytes
+        {
+            bail!("Response of {length} bytes exceeds the size limit");
+        }
+
+        let encoding = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|content_type| {
+                content_type
+                    .split(';')
+                    .skip(1)
+                    .find_map(|param| param.trim().strip_prefix("charset="))
+            })
+            .and_then(|charset| {
+                encoding_rs::Encoding::for_label(charset.trim_matches('"').as_bytes())
+            })
+            .unwrap_or(encoding_rs::UTF_8);
+
+        // Servers may not send a length, or lie about it, so keep counting as we read.
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.context("Failed to read response")? {
+            if (body.len() + chunk.len()) as u64 > self.max_response_bytes {
+                bail!(
+                    "Response exceeds the size limit of {} bytes",
+                    self.max_response_bytes
+                );
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        let (text, _, _) = encoding.decode(&body);
+        Ok(text.into_owned())
+    }
+}
+
+/// Resolves a feed's credentials into the headers that carry them.
+fn auth_headers(auth: &FeedAuth) -> anyhow::Result<HeaderMap> {
+    // Keep credentials out of debug output.
+    let sensitive_value = |value: &str| -> anyhow::Result<HeaderValue> {
+        let mut value = HeaderValue::from_str(value).context("Invalid header value")?;
+        value.set_sensitive(true);
+        Ok(value)
+    };
+
+    if auth.basic.is_some() && auth.bearer.is_some() {
+        bail!("Basic and bearer authentication can't be used together");
+    }
+
+    let mut headers = HeaderMap::new();
+
+    if let Some(basic) = &auth.basic {
+        let password = match &basic.password {
+            Some(password) => password.resolve()?,
+            None => String::new(),
+        };
+        let credentials = base64::engine::general_purpose::STANDARD
+            .encode(format!("{}:{password}", basic.username));
+        headers.insert(
+            header::AUTHORIZATION,
+            sensitive_value(&format!("Basic {credentials}"))?,
+        );
+    }
+
+    if let Some(bearer) = &auth.bearer {
+        let token = bearer.resolve()?;
+        headers.insert(
+            header::AUTHORIZATION,
+            sensitive_value(&format!("Bearer {token}"))?,
+        );
+    }
+
+    if let Some(cookie) = &auth.cookie {
+        headers.insert(header::COOKIE, sensitive_value(&cookie.resolve()?)?);
+    }
+
+    for (name, value) in &auth.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid header name {name}"))?;
+        headers.insert(name, sensitive_value(&value.resolve()?)?);
+    }
+
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::auth_headers;
+    use crate::config::{BasicAuth, FeedAuth, Secret};
+    use reqwest::header;
+
+    #[test]
+    fn test_auth_headers() {
+        let basic = BasicAuth {
+            username: "me".to_owned(),
+            password: Some(Secret::Inline("secret".to_owned())),
+        };
+        let auth = FeedAuth {
+            basic: Some(basic),
+            ..Default::default()
+        };
+        let headers = auth_headers(&auth).unwrap();
+        assert_eq!(headers[header::AUTHORIZATION], "Basic bWU6c2VjcmV0");
+        assert!(headers[header::AUTHORIZATION].is_sensitive());
+
+        // One would silently replace the other.
+        let auth = FeedAuth {
+            bearer: Some(Secret::Inline("token".to_owned())),
+            ..auth
+        };
+        assert!(auth_headers(&auth).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! JSON Feed 1.1 support.
+//!
+//! See <https://www.jsonfeed.org/version/1.1/>.
+
+use crate::storage::{StoredFeed, item_pub_date, item_to_guid};
+use anyhow::Context;
+use rss::{Category, Channel, Enclosure, Guid, Item, extension::dublincore::DublinCoreExtension};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// The MIME type for JSON Feed documents.
+pub const MIME_TYPE: &str = "application/feed+json";
+
+/// The version identifier we publish with.
+const VERSION: &str = "https://jsonfeed.org/version/1.1";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct JsonFeed {
+    pub version: String,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub home_page_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feed_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub items: Vec<JsonFeedItem>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct JsonFeedItem {
+    #[serde(deserialize_with = "string_or_number")]
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_html: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_published: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_modified: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<JsonFeedAuthor>,
+    /// Deprecated in 1.1 in favour of `authors`, still seen in 1.0 feeds.
+    #[serde(default, skip_serializing)]
+    pub author: Option<JsonFeedAuthor>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<JsonFeedAttachment>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct JsonFeedAuthor {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct JsonFeedAttachment {
+    pub url: String,
+    pub mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_in_bytes: Option<u64>,
+}
+
+/// Returns whether a response looks like a JSON Feed document, going by its
+/// content type or, failing that, by its payload.
+pub fn is_json_feed(content_type: Option<&str>, content: &str) -> bool {
+    match content_type {
+        Some(content_type) if content_type.contains("json") => true,
+        _ => content.trim_start().starts_with('{'),
+    }
+}
+
+/// Parses a JSON Feed document and normalizes it into an RSS channel.
+pub fn read_channel(content: &str) -> anyhow::Result<Channel> {
+    let feed: JsonFeed = serde_json::from_str(content).context("Failed to parse JSON feed")?;
+
+    let channel = Channel {
+        title: feed.title,
+        link: feed.home_page_url.unwrap_or_default(),
+        description: feed.description.unwrap_or_default(),
+        items: feed.items.into_iter().map(item_to_rss).collect(),
+        ..Default::default()
+    };
+
+    Ok(channel)
+}
+
+/// Turns a JSON Feed item into an RSS item.
+fn item_to_rss(item: JsonFeedItem) -> Item {
+    let guid = Guid {
+        permalink: item.url.as_deref() == Some(item.id.as_str()),
+        value: item.id,
+    };
+
+    // Fold the deprecated single author into the list.
+    let mut authors: Vec<String> = item.authors.into_iter().filter_map(|a| a.name).collect();
+    if authors.is_empty() {
+        authors.extend(item.author.and_then(|author| author.name));
+    }
+    let author = authors.first().cloned();
+    let dublin_core_ext = (!authors.is_empty()).then(|| DublinCoreExtension {
+        creators: authors,
+        ..Default::default()
+    });
+
+    // Keep the first attachment, RSS only has room for one.
+    let enclosure = item
+        .attachments
+        .into_iter()
+        .next()
+        .map(|attachment| Enclosure {
+            url: attachment.url,
+            length: attachment.size_in_bytes.unwrap_or(0).to_string(),
+            mime_type: attachment.mime_type,
+        });
+
+    let categories = item
+        .tags
+        .into_iter()
+        .map(|tag| Category {
+            name: tag,
+            domain: None,
+        })
+        .collect();
+
+    let pub_date = item
+        .date_published
+        .or(item.date_modified)
+        .and_then(|date| chrono::DateTime::parse_from_rfc3339(&date).ok())
+        .map(|date| date.to_rfc2822());
+
+    Item {
+        title: item.title,
+        link: item.url,
+        description: item.summary,
+        author,
+        categories,
+        enclosure,
+        guid: Some(guid),
+        pub_date,
+        content: item.content_html.or(item.content_text),
+        dublin_core_ext,
+        ..Default::default()
+    }
+}
+
+/// Renders a stored feed as a JSON Feed document.
+///
+/// `self_url` is the address the feed is being served at.
+pub fn write_feed(feed: &StoredFeed, self_url: &str) -> String {
+    let json_feed = JsonFeed {
+        version: VERSION.to_owned(),
+        title: feed.title.clone(),
+        home_page_url: None,
+        feed_url: Some(self_url.to_owned()),
+        description: (!feed.description.is_empty()).then(|| feed.description.clone()),
+        items: feed.items.iter().map(item_from_rss).collect(),
+    };
+
+    serde_json::to_string(&json_feed).expect("JSON feed should always serialize")
+}
+
+/// Turns an RSS item into a JSON Feed item.
+fn item_from_rss(item: &Item) -> JsonFeedItem {
+    // Prefer the full list of names from Dublin Core over the single RSS author.
+    let authors = match item.dublin_core_ext() {
+        Some(dublin_core) if !dublin_core.creators().is_empty() => dublin_core.creators().to_vec(),
+        _ => item.author().map(str::to_owned).into_iter().collect(),
+    };
+
+    JsonFeedItem {
+        id: item_to_guid(item),
+        url: item.link().map(str::to_owned),
+        title: item.title().map(str::to_owned),
+        content_html: item.content().map(str::to_owned),
+        summary: item.description().map(str::to_owned),
+        date_published: item_pub_date(item).map(|date| date.to_rfc3339()),
+        authors: authors
+            .into_iter()
+            .map(|name| JsonFeedAuthor {
+                name: Some(name),
+                url: None,
+            })
+            .collect(),
+        tags: item
+            .categories()
+            .iter()
+            .map(|category| category.name().to_owned())
+            .collect(),
+        attachments: item
+            .enclosure()
+            .map(|enclosure| JsonFeedAttachment {
+                url: enclosure.url().to_owned(),
+                mime_type: enclosure.mime_type().to_owned(),
+                size_in_bytes: enclosure.length().parse().ok().filter(|&size| size > 0),
+            })
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Item ids should be strings, but some publishers emit plain numbers.
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber {
+        String(String),
+        Number(serde_json::Number),
+    }
+
+    Ok(match StringOrNumber::deserialize(deserializer)? {
+        StringOrNumber::String(string) => string,
+        StringOrNumber::Number(number) => number.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    const FEED_JSON: &str = r#"{
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": "Example Blog",
+        "home_page_url": "https://example.org/",
+        "feed_url": "https://example.org/feed.json",
+        "description": "Notes and essays",
+        "items": [
+            {
+                "id": 2347259,
+                "url": "https://example.org/2025/07/notes",
+                "title": "Some notes",
+                "content_html": "<p>Hello, world!</p>",
+                "summary": "A short note.",
+                "date_published": "2025-07-09T11:28:42-07:00",
+                "authors": [{ "name": "Jane Doe", "url": "https://example.org/jane" }],
+                "tags": ["notes"],
+                "attachments": [
+                    { "url": "https://example.org/notes.mp3", "mime_type": "audio/mpeg", "size_in_bytes": 4096 }
+                ]
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_read_channel() {
+        assert!(super::is_json_feed(None, FEED_JSON));
+
+        let channel = super::read_channel(FEED_JSON).expect("Failed to parse JSON feed");
+        assert_eq!(channel.title(), "Example Blog");
+        assert_eq!(channel.link(), "https://example.org/");
+        assert_eq!(channel.description(), "Notes and essays");
+
+        let item = channel.items().first().expect("No items in feed");
+        assert_eq!(item.guid().unwrap().value(), "2347259");
+        assert_eq!(item.link(), Some("https://example.org/2025/07/notes"));
+        assert_eq!(item.title(), Some("Some notes"));
+        assert_eq!(item.description(), Some("A short note."));
+        assert_eq!(item.content(), Some("<p>Hello, world!</p>"));
+        assert_eq!(item.author(), Some("Jane Doe"));
+        assert_eq!(item.pub_date(), Some("Wed, 9 Jul 2025 11:28:42 -0700"));
+        assert_eq!(item.categories()[0].name(), "notes");
+
+        let enclosure = item.enclosure().expect("No enclosure in item");
+        assert_eq!(enclosure.url(), "https://example.org/notes.mp3");
+        assert_eq!(enclosure.length(), "4096");
+        assert_eq!(enclosure.mime_type(), "audio/mpeg");
+    }
+}
@@ -1,17 +1,26 @@
+mod atom;
 mod config;
 mod filter;
+mod health;
+mod http;
+mod jsonfeed;
+mod metrics;
 mod poller;
 mod server;
 mod storage;
+mod usage;
 
 use anyhow::Context;
-use filter::LLMFilter;
+use filter::{DecisionCache, LLMFilter};
 use futures::StreamExt;
+use health::HealthTracker;
+use metrics::Metrics;
 use poller::FeedPoller;
 use signal_hook::consts::{SIGINT, SIGQUIT, SIGTERM};
 use signal_hook_tokio::Signals;
 use storage::FeedStorage;
 use tracing_subscriber::prelude::*;
+use usage::UsageTracker;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -43,10 +52,20 @@ async fn main() -> anyhow::Result<()> {
         let mut config: config::Config =
             toml::from_str(&content).context("Failed to deserialize config file")?;
 
-        // Place known_items_file in the same directory as the config file.
-        let mut known_items_file = config_path;
-        known_items_file.set_file_name(&config.known_items_file);
-        config.known_items_file = known_items_file;
+        // Place our data files in the same directory as the config file.
+        config.known_items_file = config_path.with_file_name(&config.known_items_file);
+        config.filtered_items_file = config_path.with_file_name(&config.filtered_items_file);
+        config.rejected_items_file = config_path.with_file_name(&config.rejected_items_file);
+        config.decision_cache_file = config_path.with_file_name(&config.decision_cache_file);
+        config.usage_file = config_path.with_file_name(&config.usage_file);
+        config.pending_items_file = config_path.with_file_name(&config.pending_items_file);
+        config.database_file = config_path.with_file_name(&config.database_file);
+
+        // Every feed must be reachable at its own path.
+        for feed_name in config.feeds.keys() {
+            server::check_feed_name(feed_name)
+                .with_context(|| format!("Invalid feed name {feed_name} in configuration"))?;
+        }
 
         config
     };
@@ -54,12 +73,51 @@ async fn main() -> anyhow::Result<()> {
 
     //
     // Initialize components.
-    let storage = FeedStorage::new(config.max_items_per_feed, config.known_items_file.clone());
-    let llm_filter = LLMFilter::new(config.clone())?;
-    let poller = FeedPoller::new(config.clone(), storage.clone(), llm_filter);
+    let storage = FeedStorage::new(&config)?;
+    let metrics = Metrics::default();
+    let decision_cache = DecisionCache::new(
+        config.decision_cache_file.clone(),
+        config.llm.cache_ttl_seconds,
+        config.llm.cache_max_entries,
+    );
+    let usage = UsageTracker::new(
+        config.usage_file.clone(),
+        config.llm.daily_budget,
+        config.llm.monthly_budget,
+    );
+    let llm_filter = LLMFilter::new(
+        config.clone(),
+        decision_cache.clone(),
+        metrics.clone(),
+        usage.clone(),
+    )?;
+    let health = HealthTracker::default();
+    let poller = FeedPoller::new(config.clone(), storage.clone(), llm_filter, health.clone())?;
+
+    // Load known items, and the items we were serving, from disk.
+    // Feeds since removed from the configuration are no longer served.
+    let feed_names: Vec<String> = config.feeds.keys().cloned().collect();
+    storage
+        .run(move |storage| {
+            storage.load_known_items()?;
+            storage.load_filtered_items()?;
+            for feed_name in storage.feed_names()? {
+                if !feed_names.contains(&feed_name) {
+                    tracing::info!("Dropping feed {feed_name}, no longer in the configuration");
+                    storage.remove_channel(&feed_name)?;
+                }
+            }
+            Ok(())
+        })
+        .await?;
 
-    // Load known items from disk.
-    storage.write().await.load_known_items()?;
+    // The cache only saves money, so don't refuse to start over it.
+    if let Err(error) = decision_cache.load() {
+        tracing::warn!("Failed to load decision cache, starting afresh: {error:#}");
+    }
+
+    // Budgets can't be enforced without our usage so far.
+    usage.load().context("Failed to load LLM usage")?;
 
     //
     // Spawn our polling task.
@@ -67,7 +125,13 @@ async fn main() -> anyhow::Result<()> {
 
     //
     // Launch an HTTP server to serve the filtered feeds.
-    let app = server::create_router(storage.clone());
+    let app = server::create_router(
+        storage.clone(),
+        health,
+        metrics,
+        usage.clone(),
+        config.public_url.clone(),
+    );
     let addr = format!("{}:{}", config.server_host, config.server_port);
 
     tracing::info!("Starting HTTP server on {}", addr);
@@ -79,17 +143,14 @@ async fn main() -> anyhow::Result<()> {
 
     //
     // Handle signals.
-    let mut signals = Signals::new(&[SIGTERM, SIGINT, SIGQUIT]).unwrap();
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGQUIT]).unwrap();
 
     // Sends a message to shutdown_recv if any of the signals are received.
     let (shutdown_send, shutdown_recv) = tokio::sync::oneshot::channel::<()>();
     tokio::spawn(async move {
-        while let Some(signal) = signals.next().await {
+        if let Some(signal) = signals.next().await {
             match signal {
-                SIGTERM | SIGINT | SIGQUIT => {
-                    shutdown_send.send(()).unwrap();
-                    break;
-                }
+                SIGTERM | SIGINT | SIGQUIT => shutdown_send.send(()).unwrap(),
                 _ => unreachable!(),
             }
         }
@@ -103,8 +164,19 @@ async fn main() -> anyhow::Result<()> {
         _ = poller_handle => tracing::error!("Feed poller stopped unexpectedly, shutting down"),
     }
 
-    // Store our list of known items on exit.
-    storage.read().await.save_known_items()?;
+    // Store our list of known items, and the items we're serving, on exit.
+    storage
+        .run(|storage| {
+            storage.save_known_items()?;
+            storage.save_filtered_items()
+        })
+        .await?;
+    tokio::task::spawn_blocking(move || {
+        decision_cache.save()?;
+        usage.save()
+    })
+    .await
+    .context("Saving the decision cache and LLM usage panicked")??;
 
     Ok(())
 }
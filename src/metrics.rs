@@ -0,0 +1,78 @@
+//! Counters for how the LLM filter is faring, served at `/metrics`.
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Default)]
+struct Counters {
+    llm_responses: AtomicU64,
+    llm_parse_failures: AtomicU64,
+    llm_retries: AtomicU64,
+    llm_failovers: AtomicU64,
+}
+
+/// Shared counters, cheap to clone.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Counters>,
+}
+
+impl Metrics {
+    /// Records a response received from the LLM.
+    pub fn record_llm_response(&self) {
+        self.inner.llm_responses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an LLM response we couldn't make sense of.
+    pub fn record_llm_parse_failure(&self) {
+        self.inner
+            .llm_parse_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a request retried after a transient error.
+    pub fn record_llm_retry(&self) {
+        self.inner.llm_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a request passed on to a fallback provider.
+    pub fn record_llm_failover(&self) {
+        self.inner.llm_failovers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Writes every counter out in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "sane_rss_llm_responses_total",
+                "Responses received from the LLM.",
+                &self.inner.llm_responses,
+            ),
+            (
+                "sane_rss_llm_parse_failures_total",
+                "LLM responses that could not be parsed.",
+                &self.inner.llm_parse_failures,
+            ),
+            (
+                "sane_rss_llm_retries_total",
+                "LLM requests retried after a transient error.",
+                &self.inner.llm_retries,
+            ),
+            (
+                "sane_rss_llm_failovers_total",
+                "LLM requests passed on to a fallback provider.",
+                &self.inner.llm_failovers,
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, help, counter) in counters {
+            let value = counter.load(Ordering::Relaxed);
+            let _ = writeln!(output, "# HELP {name} {help}");
+            let _ = writeln!(output, "# TYPE {name} counter");
+            let _ = writeln!(output, "{name} {value}");
+        }
+        output
+    }
+}
@@ -3,119 +3,594 @@
 use anyhow::Context;
 
 use crate::{
-    config::{Config, FeedConfig},
-    filter::LLMFilter,
-    storage::FeedStorage,
+    atom,
+    config::{Config, FeedConfig, LLMErrorPolicy},
+    filter::{FilterDecision, LLMFilter},
+    health::HealthTracker,
+    http::FeedClient,
+    jsonfeed,
+    storage::{CacheValidators, FeedStorage, PendingItem, RejectedItem},
 };
-use std::time::Duration;
+use chrono::{TimeDelta, Utc};
+use futures::{StreamExt, stream::FuturesUnordered};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+
+/// Randomly stretch polling intervals by up to this fraction, so feeds don't all fire at once.
+const POLLING_JITTER: f64 = 0.1;
+
+/// Never wait longer than this between polls, whatever the feed itself asks for.
+const MAX_UPSTREAM_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Failing feeds back off exponentially, up to this interval.
+const MAX_BACKOFF_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Scheduling state for a single feed.
+struct FeedSchedule {
+    /// When the feed is next due to be polled.
+    next_due: Instant,
+
+    /// Whether a poll of this feed is in progress.
+    polling: bool,
+
+    /// The minimum polling interval the feed last asked for.
+    upstream_interval: Option<Duration>,
+}
 
 pub struct FeedPoller {
     config: Config,
     storage: FeedStorage,
     filter: LLMFilter,
+    health: HealthTracker,
+
+    /// The HTTP client for each feed.
+    clients: HashMap<String, FeedClient>,
+
+    /// Bounds how many feeds we retrieve at the same time.
+    fetch_permits: Semaphore,
+
+    /// How long deferred items are retried before we give up on the LLM.
+    max_defer: TimeDelta,
 }
 
 impl FeedPoller {
-    pub fn new(config: Config, storage: FeedStorage, filter: LLMFilter) -> Self {
-        Self {
+    pub fn new(
+        config: Config,
+        storage: FeedStorage,
+        filter: LLMFilter,
+        health: HealthTracker,
+    ) -> anyhow::Result<Self> {
+        // Feeds without overrides or credentials share the global client.
+        let global_client = FeedClient::new(&config.http, None)?;
+        let mut clients = HashMap::new();
+        for (feed_name, feed_config) in &config.feeds {
+            let client = match (&feed_config.http, &feed_config.auth) {
+                (None, None) => global_client.clone(),
+                (overrides, auth) => {
+                    let http_config = match overrides {
+                        Some(overrides) => config.http.merged_with(overrides),
+                        None => config.http.clone(),
+                    };
+                    FeedClient::new(&http_config, auth.as_ref()).with_context(|| {
+                        format!("Invalid HTTP settings or credentials for feed {feed_name}")
+                    })?
+                }
+            };
+            clients.insert(feed_name.clone(), client);
+        }
+
+        let fetch_permits = Semaphore::new(config.max_concurrent_fetches.get());
+        let max_defer = i64::try_from(config.max_defer_seconds)
+            .ok()
+            .and_then(TimeDelta::try_seconds)
+            .context("Invalid max_defer_seconds in configuration")?;
+
+        Ok(Self {
             config,
             storage,
             filter,
-        }
+            health,
+            clients,
+            fetch_permits,
+            max_defer,
+        })
     }
 
     // Launches the periodic feed poller.
     pub async fn launch(self) {
-        let polling_interval = Duration::from_secs(self.config.polling_interval_seconds);
         tracing::info!(
-            "Starting feed poller with interval of {} seconds",
-            polling_interval.as_secs()
+            "Starting feed poller for {} feeds with a default interval of {} seconds",
+            self.config.feeds.len(),
+            self.config.polling_interval_seconds
         );
 
-        // An async periodic interval.
-        let mut interval = tokio::time::interval(polling_interval);
-        // The first tick completes immediately.
-        // To avoid immediate polling, uncomment to skip it.
-        // interval.tick().await;
+        // Every feed is due right away.
+        let now = Instant::now();
+        let mut schedules: HashMap<&str, FeedSchedule> = self
+            .config
+            .feeds
+            .keys()
+            .map(|feed_name| {
+                let schedule = FeedSchedule {
+                    next_due: now,
+                    polling: false,
+                    upstream_interval: None,
+                };
+                (feed_name.as_str(), schedule)
+            })
+            .collect();
+        for feed_name in schedules.keys() {
+            self.health.record_next_poll(feed_name, Utc::now()).await;
+        }
+
+        // Polls currently in progress.
+        let mut polls = FuturesUnordered::new();
+        let poller = &self;
 
         loop {
-            interval.tick().await;
+            // Start polling every feed that is due.
+            let now = Instant::now();
+            for (&feed_name, schedule) in &mut schedules {
+                if schedule.polling || schedule.next_due > now {
+                    continue;
+                }
 
-            tracing::debug!("[feed_poller]: Polling all feeds");
-            self.poll_feeds().await;
-        }
-    }
+                schedule.polling = true;
+                let feed_config = &self.config.feeds[feed_name];
+                polls.push(
+                    async move { (feed_name, poller.poll_feed(feed_name, feed_config).await) },
+                );
+            }
 
-    async fn poll_feeds(&self) {
-        // Go through every feed.
-        'feed_loop: for (feed_name, feed_config) in &self.config.feeds {
-            tracing::debug!("Retrieving feed {feed_name}");
-
-            // Retrieve the feed. Don't stop if it fails.
-            let channel = match retrieve_feed(feed_config).await {
-                Ok(channel) => channel,
-                Err(error) => {
-                    tracing::warn!("Retrieval error: {error}");
-                    continue 'feed_loop;
+            // Wait for a poll to finish, or for the next feed to come due.
+            let next_due = schedules
+                .values()
+                .filter(|schedule| !schedule.polling)
+                .map(|schedule| schedule.next_due)
+                .min();
+            let next_due = async {
+                match next_due {
+                    Some(next_due) => tokio::time::sleep_until(next_due.into()).await,
+                    None => std::future::pending().await,
                 }
             };
-            tracing::debug!(
-                "Retrieved {} items from feed {feed_name}",
-                channel.items().len()
-            );
 
-            let mut storage = self.storage.write().await;
+            tokio::select! {
+                Some((feed_name, outcome)) = polls.next() => {
+                    let schedule = schedules.get_mut(feed_name).unwrap();
+                    let failures = match outcome {
+                        Ok(outcome) => {
+                            if let PollOutcome::Updated { upstream_interval } = outcome {
+                                schedule.upstream_interval = upstream_interval;
+                            } else {
+                                tracing::debug!("Feed {feed_name} not modified since last poll");
+                            }
+                            self.health.record_success(feed_name).await;
+                            0
+                        }
+                        Err(error) => {
+                            tracing::warn!("Failed to poll feed {feed_name}: {error:#}");
+                            self.health.record_failure(feed_name, &error).await
+                        }
+                    };
 
-            // See if our storage knows this channel.
-            storage.add_channel(feed_name, channel.title(), channel.description());
+                    let feed_config = &self.config.feeds[feed_name];
+                    let interval =
+                        self.polling_interval(feed_config, schedule.upstream_interval, failures);
+                    tracing::debug!("Polling feed {feed_name} again in {interval:?}");
+                    schedule.next_due = Instant::now() + interval;
+                    schedule.polling = false;
+                    self.health
+                        .record_next_poll(feed_name, Utc::now() + interval)
+                        .await;
 
-            // Strip any items we've already seen from the list.
-            let mut items: Vec<rss::Item> = channel.items;
-            items.retain(|item| !storage.is_known(&feed_name, item));
+                    // Write our known and filtered items to disk after every poll, as with
+                    // overlapping polls there may never be a moment when none are in progress.
+                    self.save().await;
+                }
+                _ = next_due => (),
+            }
+        }
+    }
 
-            // Record remaining items as seen.
-            tracing::debug!("Recording {} items retained as new", items.len());
-            for unknown_item in &items {
-                storage.record_as_known(feed_name, unknown_item);
+    /// Writes our known and filtered items, the decision cache and LLM usage to disk.
+    async fn save(&self) {
+        if let Err(error) = self.storage.run(|storage| storage.save_known_items()).await {
+            tracing::warn!("Failed to write known items to file: {}", error);
+        }
+        if let Err(error) = self
+            .storage
+            .run(|storage| storage.save_filtered_items())
+            .await
+        {
+            tracing::warn!("Failed to write filtered items to file: {}", error);
+        }
+        if let Err(error) = self.filter.save_cache().await {
+            tracing::warn!("Failed to write decision cache to file: {}", error);
+        }
+        if let Err(error) = self.filter.save_usage().await {
+            tracing::warn!("Failed to write LLM usage to file: {}", error);
+        }
+    }
+
+    /// Works out how long to wait before polling a feed again.
+    ///
+    /// Uses the feed's own interval if it has one, or the global interval otherwise, but never
+    /// polls more often than the feed itself asks for. Feeds that keep failing back off
+    /// exponentially. Adds some jitter so that feeds drift apart.
+    fn polling_interval(
+        &self,
+        feed_config: &FeedConfig,
+        upstream_interval: Option<Duration>,
+        consecutive_failures: u32,
+    ) -> Duration {
+        let configured = Duration::from_secs(
+            feed_config
+                .polling_interval_seconds
+                .unwrap_or(self.config.polling_interval_seconds)
+                .get(),
+        );
+        let mut interval = configured.max(upstream_interval.unwrap_or_default());
+
+        if consecutive_failures > 0 {
+            let backoff = interval.saturating_mul(1 << consecutive_failures.min(16));
+            interval = backoff.min(MAX_BACKOFF_INTERVAL.max(interval));
+        }
+
+        interval.mul_f64(1.0 + fastrand::f64() * POLLING_JITTER)
+    }
+
+    /// Retrieves a feed, filters its new items, and stores those that are accepted.
+    async fn poll_feed(
+        &self,
+        feed_name: &str,
+        feed_config: &FeedConfig,
+    ) -> anyhow::Result<PollOutcome> {
+        tracing::debug!("Retrieving feed {feed_name}");
+
+        // Only make a conditional request if we already hold this feed's channel,
+        // as we'd otherwise have nothing to serve after a 304.
+        let validators = {
+            let feed_name = feed_name.to_owned();
+            self.storage
+                .run(move |storage| match storage.has_channel(&feed_name)? {
+                    true => storage.validators(&feed_name),
+                    false => Ok(None),
+                })
+                .await?
+        };
+
+        // Retrieve the feed, with a bounded number of retrievals in flight.
+        let response = {
+            let _permit = self.fetch_permits.acquire().await.unwrap();
+            retrieve_feed(&self.clients[feed_name], feed_config, validators).await
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(error) => {
+                // Items held back from earlier polls don't need the feed to be reachable.
+                if let Err(error) = self.filter_items(feed_name, feed_config, Vec::new()).await {
+                    tracing::warn!(
+                        "Failed to retry deferred items from feed {feed_name}: {error:#}"
+                    );
+                }
+                return Err(error);
             }
+        };
+        let (channel, validators) = match response {
+            FeedResponse::Modified {
+                channel,
+                validators,
+            } => (*channel, validators),
+            FeedResponse::NotModified => {
+                // Items held back from earlier polls are still due another try.
+                self.filter_items(feed_name, feed_config, Vec::new())
+                    .await?;
+                return Ok(PollOutcome::NotModified);
+            }
+        };
+        tracing::debug!(
+            "Retrieved {} items from feed {feed_name}",
+            channel.items().len()
+        );
+
+        let upstream_interval = upstream_interval(&channel);
 
-            // Don't hold the lock through the (slow) LLM calls.
-            drop(storage);
+        let owned_feed_name = feed_name.to_owned();
+        let items = self
+            .storage
+            .run(move |storage| {
+                let feed_name = owned_feed_name.as_str();
+
+                // See if our storage knows this channel.
+                storage.add_channel(feed_name, channel.title(), channel.description())?;
+                storage.set_validators(feed_name, validators)?;
+
+                // Strip any items we've already seen from the list.
+                let mut items: Vec<rss::Item> = Vec::new();
+                for item in channel.items {
+                    if !storage.is_known(feed_name, &item)? {
+                        items.push(item);
+                    }
+                }
 
-            // Send each item to the LLM for filtering.
-            let mut accepted_items = Vec::new();
-            for item in items {
-                if self.filter.accepts(feed_name, &item).await {
-                    accepted_items.push(item);
+                // Record remaining items as seen.
+                tracing::debug!("Recording {} items retained as new", items.len());
+                for unknown_item in &items {
+                    storage.record_as_known(feed_name, unknown_item)?;
                 }
+
+                Ok(items)
+            })
+            .await?;
+
+        self.filter_items(feed_name, feed_config, items).await?;
+
+        Ok(PollOutcome::Updated { upstream_interval })
+    }
+
+    /// Filters a feed's new items, along with any held back from earlier polls, and stores them.
+    ///
+    /// Items the LLM couldn't decide on are handled according to the feed's `on_llm_error` policy.
+    async fn filter_items(
+        &self,
+        feed_name: &str,
+        feed_config: &FeedConfig,
+        new_items: Vec<rss::Item>,
+    ) -> anyhow::Result<()> {
+        let policy = feed_config.on_llm_error.unwrap_or(self.config.on_llm_error);
+        let now = Utc::now();
+
+        // Items are only held under `defer`, but the policy may have changed since.
+        let (expired_decision, giving_up) = match policy {
+            LLMErrorPolicy::Accept => (FilterDecision::without_llm(true), "accepting"),
+            LLMErrorPolicy::Reject | LLMErrorPolicy::Defer => {
+                (FilterDecision::without_llm(false), "rejecting")
             }
+        };
 
-            // If accepted, place it in our storage.
-            tracing::debug!("Filters accepted {} items, storing", accepted_items.len());
-            let mut storage = self.storage.write().await;
-            for item in accepted_items {
-                storage.store_filtered_item(&feed_name, item);
+        // Held items go first, as they're the oldest. Give up on those held for too long.
+        let mut items = Vec::new();
+        let mut deferred_at = Vec::new();
+        let mut expired = Vec::new();
+        let pending_items = {
+            let feed_name = feed_name.to_owned();
+            self.storage
+                .run(move |storage| storage.pending_items(&feed_name))
+                .await?
+        };
+        for pending in pending_items {
+            if now - pending.deferred_at > self.max_defer {
+                tracing::warn!(
+                    "Gave up waiting for the LLM on item {:?}, {giving_up} it",
+                    pending.item.title()
+                );
+                expired.push(pending.item);
+            } else {
+                items.push(pending.item);
+                deferred_at.push(Some(pending.deferred_at));
             }
         }
-
-        // At the end of each cycle, write our known items to disk.
-        if let Err(error) = self.storage.write().await.save_known_items() {
-            tracing::warn!("Failed to write known items to file: {}", error);
+        if items.is_empty() && new_items.is_empty() && expired.is_empty() {
+            return Ok(());
+        }
+        if !items.is_empty() {
+            tracing::debug!(
+                "Retrying {} deferred items from feed {feed_name}",
+                items.len()
+            );
         }
+        deferred_at.extend(new_items.iter().map(|_| None));
+        items.extend(new_items);
+
+        // Send the items to the LLM for filtering, all at once.
+        // The filter itself bounds how many requests are in flight.
+        let decisions = self.filter.decide_all(feed_name, &items).await;
+
+        // Record every decision, and place accepted items in our storage.
+        // Rejected items are kept aside for review.
+        let expired = expired
+            .into_iter()
+            .map(|item| (item, Some(expired_decision.clone()), Some(now)));
+        let decided = items
+            .into_iter()
+            .zip(decisions)
+            .zip(deferred_at)
+            .map(|((item, decision), deferred_at)| (item, decision, deferred_at));
+        let decided: Vec<_> = expired.chain(decided).collect();
+
+        let feed_name = feed_name.to_owned();
+        let annotations = self.config.annotate_items.clone();
+        let accepted_count = self
+            .storage
+            .run(move |storage| {
+                let feed_name = feed_name.as_str();
+                let mut accepted_count = 0;
+
+                for (mut item, decision, deferred_at) in decided {
+                    let decision = match (decision, policy) {
+                        (Some(decision), _) => decision,
+                        (None, LLMErrorPolicy::Defer) => {
+                            // Items already held stay where they are.
+                            if deferred_at.is_none() {
+                                tracing::info!(
+                                    "Deferring item {:?} until the LLM answers",
+                                    item.title()
+                                );
+                                let pending = PendingItem {
+                                    item,
+                                    deferred_at: now,
+                                };
+                                storage.store_pending_item(feed_name, pending)?;
+                            }
+                            continue;
+                        }
+                        (None, LLMErrorPolicy::Accept) => FilterDecision::without_llm(true),
+                        (None, LLMErrorPolicy::Reject) => FilterDecision::without_llm(false),
+                    };
+                    // Held items are only let go once stored, so a failure leaves them held.
+                    let held = deferred_at.map(|_| item.clone());
+
+                    storage.record_decision(feed_name, &item, &decision)?;
+                    if decision.accepted {
+                        decision.annotate(&mut item, &annotations);
+                        storage.store_filtered_item(feed_name, item)?;
+                        accepted_count += 1;
+                    } else {
+                        storage.store_rejected_item(feed_name, RejectedItem { item, decision })?;
+                    }
+
+                    if let Some(held) = held {
+                        storage.remove_pending_item(feed_name, &held)?;
+                    }
+                }
+
+                Ok(accepted_count)
+            })
+            .await?;
+        tracing::debug!("Filters accepted {accepted_count} items");
+
+        Ok(())
     }
 }
 
-async fn retrieve_feed(config: &FeedConfig) -> anyhow::Result<rss::Channel> {
+/// The outcome of a successful feed poll.
+enum PollOutcome {
+    /// The feed hasn't changed since our last poll.
+    NotModified,
+
+    /// The feed was retrieved and its new items filtered.
+    Updated {
+        /// The minimum polling interval the feed asks for.
+        upstream_interval: Option<Duration>,
+    },
+}
+
+/// The outcome of a feed retrieval.
+enum FeedResponse {
+    /// The server says the feed hasn't changed since our last request.
+    NotModified,
+
+    /// We got a fresh copy of the feed.
+    Modified {
+        channel: Box<rss::Channel>,
+        validators: CacheValidators,
+    },
+}
+
+/// Retrieves a feed, making a conditional request if we have validators for it.
+async fn retrieve_feed(
+    client: &FeedClient,
+    config: &FeedConfig,
+    validators: Option<CacheValidators>,
+) -> anyhow::Result<FeedResponse> {
+    use reqwest::{StatusCode, header};
+
     tracing::debug!("Retrieving feed from {}", config.url);
 
-    let response = reqwest::get(&config.url)
-        .await
-        .context("Failed to HTTP GET feed")?;
+    let mut headers = header::HeaderMap::new();
+    if let Some(validators) = validators {
+        if let Some(etag) = validators.etag {
+            headers.insert(header::IF_NONE_MATCH, etag.parse()?);
+        }
+        if let Some(last_modified) = validators.last_modified {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified.parse()?);
+        }
+    }
 
-    let content = response.text().await.context("No text in response")?;
+    let response = client.get(&config.url, headers).await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(FeedResponse::NotModified);
+    }
+    let response = response
+        .error_for_status()
+        .context("Feed server returned an error")?;
+
+    let header_value = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let validators = CacheValidators {
+        etag: header_value(header::ETAG),
+        last_modified: header_value(header::LAST_MODIFIED),
+    };
+    let content_type = header_value(header::CONTENT_TYPE);
 
-    let channel =
-        rss::Channel::read_from(content.as_bytes()).context("Failed to parse RSS feed")?;
+    let content = client.read_text(response).await?;
+    let channel = parse_feed(content_type.as_deref(), &content)?;
 
-    Ok(channel)
+    Ok(FeedResponse::Modified {
+        channel: Box::new(channel),
+        validators,
+    })
+}
+
+/// Parses a feed document, accepting RSS, Atom and JSON Feed.
+fn parse_feed(content_type: Option<&str>, content: &str) -> anyhow::Result<rss::Channel> {
+    if jsonfeed::is_json_feed(content_type, content) {
+        return jsonfeed::read_channel(content);
+    }
+
+    match rss::Channel::read_from(content.as_bytes()) {
+        Ok(channel) => Ok(channel),
+
+        // Not an RSS document, see if it's an Atom feed instead.
+        Err(rss::Error::InvalidStartTag) => atom::read_channel(content.as_bytes()),
+
+        Err(error) => Err(error).context("Failed to parse RSS feed"),
+    }
+}
+
+/// Reads the minimum polling interval a channel asks for, through `<ttl>` or `sy:updatePeriod`.
+fn upstream_interval(channel: &rss::Channel) -> Option<Duration> {
+    use rss::extension::syndication::UpdatePeriod;
+
+    // The TTL is given in minutes.
+    let ttl = channel
+        .ttl()
+        .and_then(|ttl| ttl.trim().parse::<u64>().ok())
+        .map(|minutes| Duration::from_secs(minutes.saturating_mul(60)));
+
+    // The update period is split into a number of updates.
+    let update_period = channel.syndication_ext().map(|syndication| {
+        let period_hours = match syndication.period() {
+            UpdatePeriod::Hourly => 1,
+            UpdatePeriod::Daily => 24,
+            UpdatePeriod::Weekly => 24 * 7,
+            UpdatePeriod::Monthly => 24 * 30,
+            UpdatePeriod::Yearly => 24 * 365,
+        };
+        Duration::from_secs(period_hours * 60 * 60) / syndication.frequency().max(1)
+    });
+
+    ttl.max(update_period)
+        .map(|interval| interval.min(MAX_UPSTREAM_INTERVAL))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_UPSTREAM_INTERVAL, upstream_interval};
+    use std::time::Duration;
+
+    #[test]
+    fn test_upstream_interval() {
+        let mut channel = rss::Channel::default();
+        assert_eq!(upstream_interval(&channel), None);
+
+        channel.set_ttl("30".to_owned());
+        assert_eq!(
+            upstream_interval(&channel),
+            Some(Duration::from_secs(30 * 60))
+        );
+
+        // Absurd TTLs are capped rather than overflowing.
+        channel.set_ttl(u64::MAX.to_string());
+        assert_eq!(upstream_interval(&channel), Some(MAX_UPSTREAM_INTERVAL));
+    }
 }
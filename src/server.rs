@@ -1,37 +1,330 @@
-use crate::storage::FeedStorage;
+use crate::{
+    atom,
+    filter::escape_html,
+    health::HealthTracker,
+    jsonfeed,
+    metrics::Metrics,
+    storage::{FeedStorage, RejectedItem, StoredFeed},
+    usage::UsageTracker,
+};
+use anyhow::bail;
 use axum::{
-    Router,
-    extract::{Path, State},
-    http::StatusCode,
+    Json, Router,
+    extract::{FromRef, Path, State},
+    http::{HeaderMap, StatusCode, header},
     response::{IntoResponse, Response},
     routing::get,
 };
 use rss::ChannelBuilder;
+use std::sync::Arc;
+use url::Url;
+
+/// The formats we can republish a feed in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FeedFormat {
+    Rss,
+    Atom,
+    Json,
+}
+
+impl FeedFormat {
+    /// Picks a format for a request to `/{feed_name}`.
+    ///
+    /// An explicit suffix (`.rss`, `.atom`, `.json`) wins over the `Accept` header.
+    /// Returns the feed name with the suffix stripped.
+    fn negotiate<'a>(feed_name: &'a str, headers: &HeaderMap) -> (&'a str, Self) {
+        if let Some((name, extension)) = feed_name.rsplit_once('.')
+            && let Some(format) = Self::from_extension(extension)
+        {
+            return (name, format);
+        }
+
+        // Go through the accepted media types in order, take the first we know.
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let format = accept
+            .split(',')
+            .filter_map(|media_type| {
+                match media_type.split(';').next().unwrap_or_default().trim() {
+                    "application/rss+xml" => Some(Self::Rss),
+                    atom::MIME_TYPE => Some(Self::Atom),
+                    jsonfeed::MIME_TYPE | "application/json" => Some(Self::Json),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(Self::Rss);
+
+        (feed_name, format)
+    }
+
+    /// Picks a format from a feed path's extension, like `rss` in `/news.rss`.
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "rss" | "xml" => Some(Self::Rss),
+            "atom" => Some(Self::Atom),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Rss => "rss",
+            Self::Atom => "atom",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// The URL readers reach us at, if configured.
+#[derive(Clone)]
+struct PublicUrl(Option<Arc<Url>>);
+
+/// Shared state for our handlers.
+#[derive(Clone)]
+struct AppState {
+    storage: FeedStorage,
+    health: HealthTracker,
+    metrics: Metrics,
+    usage: UsageTracker,
+    public_url: PublicUrl,
+}
+
+impl FromRef<AppState> for FeedStorage {
+    fn from_ref(state: &AppState) -> Self {
+        state.storage.clone()
+    }
+}
+
+impl FromRef<AppState> for HealthTracker {
+    fn from_ref(state: &AppState) -> Self {
+        state.health.clone()
+    }
+}
+
+impl FromRef<AppState> for Metrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for UsageTracker {
+    fn from_ref(state: &AppState) -> Self {
+        state.usage.clone()
+    }
+}
+
+impl FromRef<AppState> for PublicUrl {
+    fn from_ref(state: &AppState) -> Self {
+        state.public_url.clone()
+    }
+}
 
-pub fn create_router(storage: FeedStorage) -> Router {
+/// Paths of our own endpoints, which feeds can't be served at.
+const RESERVED_PATHS: &[&str] = &["feeds", "status", "metrics", "usage"];
+
+/// Makes sure a feed can be reached at `/{feed_name}`.
+///
+/// Names ending in a format's extension would be taken for another feed in that format.
+pub fn check_feed_name(feed_name: &str) -> anyhow::Result<()> {
+    if feed_name.is_empty() || feed_name.contains('/') {
+        bail!("Feed names can't be empty or contain slashes");
+    }
+    if RESERVED_PATHS.contains(&feed_name) {
+        bail!(
+            "Feed names can't be any of {}, which are used by the server",
+            RESERVED_PATHS.join(", ")
+        );
+    }
+    if let Some((_, extension)) = feed_name.rsplit_once('.')
+        && FeedFormat::from_extension(extension).is_some()
+    {
+        bail!("Feed names can't end in .rss, .xml, .atom or .json");
+    }
+
+    Ok(())
+}
+
+pub fn create_router(
+    storage: FeedStorage,
+    health: HealthTracker,
+    metrics: Metrics,
+    usage: UsageTracker,
+    public_url: Option<Url>,
+) -> Router {
     Router::new()
+        // Any new endpoint here needs its path in `RESERVED_PATHS`.
         .route("/feeds", get(list_feeds))
+        .route("/status", get(feed_status))
+        .route("/metrics", get(serve_metrics))
+        .route("/usage", get(serve_usage))
         .route("/{feed_name}", get(serve_feed))
-        .with_state(storage)
+        .route("/{feed_name}/{listing}", get(serve_rejected_feed))
+        .with_state(AppState {
+            storage,
+            health,
+            metrics,
+            usage,
+            public_url: PublicUrl(public_url.map(Arc::new)),
+        })
 }
 
-async fn serve_feed(Path(feed_name): Path<String>, State(storage): State<FeedStorage>) -> Response {
-    let storage = storage.read().await;
+async fn serve_feed(
+    Path(feed_name): Path<String>,
+    State(storage): State<FeedStorage>,
+    State(public_url): State<PublicUrl>,
+    headers: HeaderMap,
+) -> Response {
+    let (feed_name, format) = FeedFormat::negotiate(&feed_name, &headers);
+    let feed = {
+        let feed_name = feed_name.to_owned();
+        storage.run(move |storage| storage.feed(&feed_name)).await
+    };
 
     // Do we have the requested feed?
-    match storage.feeds.get(&feed_name) {
+    match feed {
+        Err(error) => {
+            tracing::warn!("Failed to read feed {feed_name} from storage: {error:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read feed").into_response()
+        }
+
         // Nope.
-        None => (StatusCode::NOT_FOUND, "Feed not found").into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Feed not found").into_response(),
 
         // Yup.
-        Some(feed) => {
-            tracing::debug!("Serving feed: {feed_name} with {} items", feed.items.len());
+        Ok(Some(feed)) => {
+            tracing::debug!(
+                "Serving feed: {feed_name} with {} items as {format:?}",
+                feed.items.len()
+            );
+            let self_url = self_url(&public_url, &headers, feed_name, format);
+            render_feed(feed, format, &self_url)
+        }
+    }
+}
+
+/// Serves the items the filter rejected from a feed, at `/{feed_name}/rejected`.
+///
+/// Each item carries the filter's decision, so that over-eager filtering can be spotted.
+async fn serve_rejected_feed(
+    Path((feed_name, listing)): Path<(String, String)>,
+    State(storage): State<FeedStorage>,
+    State(public_url): State<PublicUrl>,
+    headers: HeaderMap,
+) -> Response {
+    let (listing, format) = FeedFormat::negotiate(&listing, &headers);
+    if listing != "rejected" {
+        return (StatusCode::NOT_FOUND, "Feed not found").into_response();
+    }
+
+    let read = {
+        let feed_name = feed_name.clone();
+        storage
+            .run(move |storage| {
+                Ok((
+                    storage.feed(&feed_name)?,
+                    storage.rejected_items(&feed_name)?,
+                ))
+            })
+            .await
+    };
+    let (feed, rejected_items) = match read {
+        Ok(read) => read,
+        Err(error) => {
+            tracing::warn!("Failed to read rejected items of {feed_name} from storage: {error:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read feed").into_response();
+        }
+    };
+
+    let Some(feed) = feed else {
+        return (StatusCode::NOT_FOUND, "Feed not found").into_response();
+    };
+
+    tracing::debug!(
+        "Serving rejected items of feed: {feed_name} with {} items as {format:?}",
+        rejected_items.len()
+    );
+    let feed = StoredFeed {
+        title: format!("{} (rejected)", feed.title),
+        description: format!("Items rejected by the filter from {}", feed.title),
+        items: rejected_items
+            .into_iter()
+            .map(annotate_rejected_item)
+            .collect(),
+    };
+    let path = format!("{feed_name}/rejected");
+    render_feed(
+        feed,
+        format,
+        &self_url(&public_url, &headers, &path, format),
+    )
+}
+
+/// Notes the filter's decision at the top of a rejected item's description.
+fn annotate_rejected_item(rejected: RejectedItem) -> rss::Item {
+    let RejectedItem { mut item, decision } = rejected;
+
+    let mut note = format!("Rejected on {}", decision.decided_at.to_rfc2822());
+    if let Some(response) = &decision.response {
+        note.push_str(&format!(
+            " (accept: {}, reject: {})",
+            response.accept, response.reject
+        ));
+        if let Some(reason) = &response.reason {
+            note.push_str(&format!(": {reason}"));
+        }
+        if !response.topics.is_empty() {
+            note.push_str(&format!(" [topics: {}]", response.topics.join(", ")));
+        }
+    }
+    let note = escape_html(&note);
+
+    let description = match item.description() {
+        Some(description) => format!("<p>{note}</p>{description}"),
+        None => format!("<p>{note}</p>"),
+    };
+    item.set_description(description);
+    item
+}
+
+/// Builds the URL Atom and JSON Feed documents link back to themselves with.
+///
+/// Without a configured public URL, we trust the scheme and host a reverse proxy passes along.
+fn self_url(public_url: &PublicUrl, headers: &HeaderMap, path: &str, format: FeedFormat) -> String {
+    let base = match &public_url.0 {
+        Some(public_url) => public_url.as_str().trim_end_matches('/').to_owned(),
+        None => {
+            // Proxies in a chain append their own values; the first is the client's.
+            let header = |name| {
+                headers
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.split(',').next())
+                    .map(str::trim)
+            };
+            let scheme = header("x-forwarded-proto").unwrap_or("http");
+            let host = header("x-forwarded-host")
+                .or(header(header::HOST.as_str()))
+                .unwrap_or("localhost");
+            format!("{scheme}://{host}")
+        }
+    };
+
+    format!("{base}/{path}.{}", format.extension())
+}
 
+/// Writes a feed out in the requested format.
+fn render_feed(feed: StoredFeed, format: FeedFormat, self_url: &str) -> Response {
+    match format {
+        FeedFormat::Rss => {
             // Prepare a feed to serve.
             let channel = ChannelBuilder::default()
                 .title(&feed.title)
                 .description(&feed.description)
-                .items(feed.items.clone())
+                .items(feed.items)
                 .build();
 
             // Turn it into RSS XML and serve.
@@ -39,23 +332,95 @@ async fn serve_feed(Path(feed_name): Path<String>, State(storage): State<FeedSto
             let rss_content = [("content-type", "application/rss+xml")];
             (StatusCode::OK, rss_content, rss_string).into_response()
         }
+        FeedFormat::Atom => {
+            let atom_content = [("content-type", atom::MIME_TYPE)];
+            let atom_string = atom::write_feed(&feed, self_url);
+            (StatusCode::OK, atom_content, atom_string).into_response()
+        }
+        FeedFormat::Json => {
+            let json_content = [("content-type", jsonfeed::MIME_TYPE)];
+            let json_string = jsonfeed::write_feed(&feed, self_url);
+            (StatusCode::OK, json_content, json_string).into_response()
+        }
     }
 }
 
 async fn list_feeds(State(storage): State<FeedStorage>) -> Response {
-    let storage = storage.read().await;
+    let feed_names = match storage.run(|storage| storage.feed_names()).await {
+        Ok(feed_names) => feed_names,
+        Err(error) => {
+            tracing::warn!("Failed to list feeds from storage: {error:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to list feeds").into_response();
+        }
+    };
 
-    let content = if storage.feeds.is_empty() {
+    let content = if feed_names.is_empty() {
         "No feeds available yet".to_string()
     } else {
-        let feed_list: Vec<String> = storage
-            .feeds
-            .keys()
-            .map(|name| format!("- /{name}"))
-            .collect();
+        let feed_list: Vec<String> = feed_names.iter().map(|name| format!("- /{name}")).collect();
 
         format!("Available feeds:\n{}", feed_list.join("\n"))
     };
 
     (StatusCode::OK, content).into_response()
 }
+
+/// Reports how each feed has been faring, so that dead feeds get noticed.
+async fn feed_status(State(health): State<HealthTracker>) -> Response {
+    (StatusCode::OK, Json(health.snapshot().await)).into_response()
+}
+
+/// Serves our counters for scraping by Prometheus.
+async fn serve_metrics(State(metrics): State<Metrics>) -> Response {
+    let content_type = [("content-type", "text/plain; version=0.0.4")];
+    (StatusCode::OK, content_type, metrics.render()).into_response()
+}
+
+/// Reports the tokens and money spent on the LLM, per day, feed and model.
+async fn serve_usage(State(usage): State<UsageTracker>) -> Response {
+    (StatusCode::OK, Json(usage.report())).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FeedFormat, PublicUrl, check_feed_name, self_url};
+    use axum::http::{HeaderMap, HeaderValue, header};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_self_url() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("backend:8080"));
+        let derived = PublicUrl(None);
+        assert_eq!(
+            self_url(&derived, &headers, "news", FeedFormat::Atom),
+            "http://backend:8080/news.atom"
+        );
+
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        headers.insert(
+            "x-forwarded-host",
+            HeaderValue::from_static("feeds.example.com, proxy"),
+        );
+        assert_eq!(
+            self_url(&derived, &headers, "news", FeedFormat::Json),
+            "https://feeds.example.com/news.json"
+        );
+
+        let configured = PublicUrl(Some(Arc::new(
+            "https://example.com/feeds/".parse().unwrap(),
+        )));
+        assert_eq!(
+            self_url(&configured, &headers, "news/rejected", FeedFormat::Atom),
+            "https://example.com/feeds/news/rejected.atom"
+        );
+    }
+
+    #[test]
+    fn test_check_feed_name() {
+        assert!(check_feed_name("hacker.news").is_ok());
+        assert!(check_feed_name("news.json").is_err());
+        assert!(check_feed_name("news/rejected").is_err());
+        assert!(check_feed_name("status").is_err());
+    }
+}
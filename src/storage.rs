@@ -1,9 +1,18 @@
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+use crate::config::{Config, StorageBackend};
+use crate::filter::FilterDecision;
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset, Utc};
 use rss::Item;
-use std::collections::{HashMap, VecDeque};
-use std::ops::Deref;
-use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
 
 /// Don't keep more than this number of items in the known items cache.
 ///
@@ -11,151 +20,381 @@ use tokio::sync::RwLock;
 /// returned by any feed (typically <60).
 const KNOWN_ITEMS_LIMIT: usize = 128;
 
+#[derive(Clone, Deserialize, Serialize)]
 pub struct StoredFeed {
     pub title: String,
     pub description: String,
     pub items: VecDeque<Item>,
 }
 
-#[derive(Clone)]
-pub struct FeedStorage {
-    inner: Arc<RwLock<FeedStorageInner>>,
+impl StoredFeed {
+    /// Returns the publication date of the newest item, or the current time if none are dated.
+    pub fn updated(&self) -> DateTime<FixedOffset> {
+        self.items
+            .iter()
+            .filter_map(item_pub_date)
+            .max()
+            .unwrap_or_else(|| Utc::now().fixed_offset())
+    }
 }
 
-pub struct FeedStorageInner {
-    /// A list of items we're serving to the user.
-    pub feeds: HashMap<String, StoredFeed>,
-
-    /// How many items we can keep in each feed.
-    max_items: usize,
+/// An item the filter rejected, kept so that its decision can be reviewed.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RejectedItem {
+    pub item: Item,
+    pub decision: FilterDecision,
+}
 
-    /// A list of items we've seen before (and might have filtered out).
-    ///
-    /// Note: not limited by `max_items`.
-    pub known_items: HashMap<String, VecDeque<String>>,
+/// An item the LLM couldn't decide on, held to be filtered again on a later poll.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PendingItem {
+    pub item: Item,
+    pub deferred_at: DateTime<Utc>,
+}
 
-    /// A location to store and load known items.
-    known_items_file: PathBuf,
+/// Parses the (RFC 2822) publication date of an RSS item.
+pub fn item_pub_date(item: &Item) -> Option<DateTime<FixedOffset>> {
+    item.pub_date()
+        .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
 }
 
-impl Deref for FeedStorage {
-    type Target = RwLock<FeedStorageInner>;
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+/// Turns an RSS item into a GUID.
+///
+/// If the item does not contain a GUID, we use its link or its title as a unique identifier.
+pub fn item_to_guid(item: &Item) -> String {
+    if let Some(guid) = item.guid() {
+        guid.value().to_string()
+    } else if let Some(link) = item.link() {
+        link.to_string()
+    } else if let Some(title) = item.title() {
+        format!("{}-{}", title, item.pub_date().unwrap_or("no-date"))
+    } else {
+        unreachable!()
     }
 }
 
-impl FeedStorage {
-    pub fn new(max_items: usize, known_items_file: PathBuf) -> Self {
-        Self {
-            inner: Arc::new(RwLock::new(FeedStorageInner {
-                feeds: HashMap::new(),
-                max_items,
-                known_items: HashMap::new(),
-                known_items_file,
-            })),
-        }
-    }
+/// HTTP cache validators returned by a feed's server, used to make conditional requests.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CacheValidators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
 }
 
-impl FeedStorageInner {
+/// A place to keep the feeds we serve and the items we've seen.
+///
+/// Methods may block on disk I/O, so callers go through [`FeedStorage::run`].
+pub trait Storage: Send {
+    /// Adds a new empty channel to our storage if it does not exist.
+    fn add_channel(
+        &mut self,
+        feed_name: &str,
+        title: &str,
+        description: &str,
+    ) -> anyhow::Result<()>;
+
+    /// Returns whether we hold a channel for a feed.
+    fn has_channel(&self, feed_name: &str) -> anyhow::Result<bool>;
+
+    /// Forgets a feed, along with every item and decision we hold for it.
+    fn remove_channel(&mut self, feed_name: &str) -> anyhow::Result<()>;
+
+    /// Returns a feed we're serving, with its items.
+    fn feed(&self, feed_name: &str) -> anyhow::Result<Option<StoredFeed>>;
+
+    /// Returns the names of every feed we're serving.
+    fn feed_names(&self) -> anyhow::Result<Vec<String>>;
+
     /// Store an item to be served in our filtered feeds.
-    pub fn store_filtered_item(&mut self, feed_name: &str, item: rss::Item) {
-        let feed = self
-            .feeds
-            .get_mut(feed_name)
-            .expect("Tried to record an item in an unknown feed");
+    ///
+    /// Fails if we hold no channel for the feed.
+    fn store_filtered_item(&mut self, feed_name: &str, item: Item) -> anyhow::Result<()>;
 
-        feed.items.push_back(item);
+    /// Store an item the filter rejected, to be reviewed later.
+    fn store_rejected_item(
+        &mut self,
+        feed_name: &str,
+        rejected: RejectedItem,
+    ) -> anyhow::Result<()>;
 
-        // Remove oldest items if we exceed the limit.
-        while feed.items.len() > self.max_items {
-            feed.items.pop_front();
-        }
+    /// Returns the items the filter rejected from a feed, oldest first.
+    fn rejected_items(&self, feed_name: &str) -> anyhow::Result<Vec<RejectedItem>>;
+
+    /// Holds an item the filter couldn't decide on, to be filtered again later.
+    fn store_pending_item(&mut self, feed_name: &str, pending: PendingItem) -> anyhow::Result<()>;
+
+    /// Returns the items of a feed waiting for a decision, oldest first.
+    fn pending_items(&self, feed_name: &str) -> anyhow::Result<Vec<PendingItem>>;
+
+    /// Stops holding an item, once it has been decided on.
+    fn remove_pending_item(&mut self, feed_name: &str, item: &Item) -> anyhow::Result<()>;
+
+    /// Records whether the filter accepted an item.
+    ///
+    /// Only the SQLite backend keeps every decision. The memory backend keeps none beyond
+    /// those attached to rejected items.
+    fn record_decision(
+        &mut self,
+        feed_name: &str,
+        item: &Item,
+        decision: &FilterDecision,
+    ) -> anyhow::Result<()>;
+
+    /// Returns whether an item in a given feed has been seen before.
+    fn is_known(&self, feed_name: &str, item: &Item) -> anyhow::Result<bool>;
+
+    /// Records a new item in a feed as known.
+    fn record_as_known(&mut self, feed_name: &str, item: &Item) -> anyhow::Result<()>;
+
+    /// Returns the cache validators last seen for a feed.
+    fn validators(&self, feed_name: &str) -> anyhow::Result<Option<CacheValidators>>;
+
+    /// Records the cache validators returned with a feed's latest response.
+    fn set_validators(
+        &mut self,
+        feed_name: &str,
+        validators: CacheValidators,
+    ) -> anyhow::Result<()>;
+
+    /// Writes our list of known items (and cache validators) to durable storage.
+    fn save_known_items(&self) -> anyhow::Result<()>;
+
+    /// Loads our list of known items (and cache validators) from durable storage.
+    fn load_known_items(&mut self) -> anyhow::Result<()>;
+
+    /// Writes the items we're serving (and those rejected or pending) to durable storage.
+    fn save_filtered_items(&self) -> anyhow::Result<()>;
+
+    /// Loads the items we're serving (and those rejected or pending) from durable storage.
+    fn load_filtered_items(&mut self) -> anyhow::Result<()>;
+}
+
+/// Suffix for the copy of a file's previous contents.
+const BACKUP_SUFFIX: &str = ".bak";
+
+/// Suffix for the file a new version is written to before replacing the original.
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Appends a suffix to a path's file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Reads and parses a JSON file.
+///
+/// Returns `None` if the file does not exist.
+pub fn read_json_file<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Option<T>> {
+    use std::io::ErrorKind;
+
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error).with_context(|| format!("Failed to read {}", path.display())),
     }
+}
 
-    /// Adds a new empty channel to our storage if it does not exist.
-    pub fn add_channel(&mut self, feed_name: &str, title: &str, description: &str) {
-        if !self.feeds.contains_key(feed_name) {
-            self.feeds.insert(
-                feed_name.to_owned(),
-                StoredFeed {
-                    title: title.to_owned(),
-                    description: description.to_owned(),
-                    items: VecDeque::new(),
-                },
-            );
+/// Replaces a file's contents by writing them to a temporary file and renaming it over the original.
+///
+/// With `keep_backup`, the previous contents are first copied next to the file. Syncs the data and
+/// the rename to disk before returning.
+pub fn write_atomically(path: &Path, contents: &[u8], keep_backup: bool) -> std::io::Result<()> {
+    use std::io::{ErrorKind, Write};
+
+    let temp_path = with_suffix(path, TEMP_SUFFIX);
+    let mut file = std::fs::File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    // Copy rather than rename, so there's always a file in place.
+    if keep_backup {
+        match std::fs::copy(path, with_suffix(path, BACKUP_SUFFIX)) {
+            Ok(_) => {}
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
         }
     }
 
-    /// Returns whether an item in a given feed has been seen before.
-    pub fn is_known(&self, feed_name: &str, item: &rss::Item) -> bool {
-        let item_guid = Self::item_to_guid(item);
+    std::fs::rename(&temp_path, path)?;
 
-        if let Some(known_feed_items) = self.known_items.get(feed_name) {
-            known_feed_items.contains(&item_guid)
-        } else {
-            false
-        }
+    // Make the rename itself durable.
+    if let Some(parent) = path.parent() {
+        std::fs::File::open(parent)?.sync_all()?;
     }
 
-    /// Records a new item in a feed as known.
-    ///
-    /// Returns false if the item already existed.
-    pub fn record_as_known(&mut self, feed_name: impl Into<String>, item: &rss::Item) {
-        let item_guid = Self::item_to_guid(item);
-        let item_guids = self.known_items.entry(feed_name.into()).or_default();
-
-        item_guids.push_back(item_guid);
-        while item_guids.len() > KNOWN_ITEMS_LIMIT {
-            item_guids.pop_front();
-        }
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct FeedStorage {
+    inner: Arc<Mutex<Box<dyn Storage>>>,
+}
+
+impl FeedStorage {
+    /// Opens the storage backend selected in the configuration.
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let storage: Box<dyn Storage> = match config.storage_backend {
+            StorageBackend::Memory => Box::new(MemoryStorage::new(
+                config.max_items_per_feed,
+                config.max_rejected_items_per_feed,
+                config.known_items_file.clone(),
+                config.filtered_items_file.clone(),
+                config.rejected_items_file.clone(),
+                config.pending_items_file.clone(),
+            )),
+            StorageBackend::Sqlite => Box::new(SqliteStorage::open(
+                &config.database_file,
+                config.max_items_per_feed,
+                config.max_rejected_items_per_feed,
+            )?),
+        };
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(storage)),
+        })
     }
 
-    /// Turns an RSS item into a GUID.
+    /// Runs an operation against our storage on a blocking thread, as it may wait on the disk.
     ///
-    /// If the item does not contain a GUID, we use its link or its title as a unique identifier.
-    fn item_to_guid(item: &rss::Item) -> String {
-        if let Some(guid) = item.guid() {
-            guid.value().to_string()
-        } else if let Some(link) = item.link() {
-            link.to_string()
-        } else if let Some(title) = item.title() {
-            format!("{}-{}", title, item.pub_date().unwrap_or("no-date"))
-        } else {
-            unreachable!()
-        }
+    /// The storage is locked for the whole operation, so it can make several changes at once.
+    pub async fn run<T, F>(&self, operation: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn Storage) -> anyhow::Result<T> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || {
+            // A panic elsewhere can't leave the storage itself in a bad state.
+            let mut storage = inner.lock().unwrap_or_else(PoisonError::into_inner);
+            operation(storage.as_mut())
+        })
+        .await
+        .context("Storage operation panicked")?
     }
+}
 
-    /// Save our list of known items to a file.
-    ///
-    /// Overwrites the file's contents.
-    pub fn save_known_items(&self) -> std::io::Result<()> {
-        tracing::debug!("Saving known items to file");
-        let json = serde_json::to_string(&self.known_items)?;
-        std::fs::write(&self.known_items_file, json)?;
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::{
+        CacheValidators, MemoryStorage, PendingItem, RejectedItem, SqliteStorage, Storage,
+    };
+    use crate::filter::FilterDecision;
+
+    fn test_item(guid: &str) -> rss::Item {
+        rss::Item {
+            title: Some(format!("Item {guid}")),
+            guid: Some(rss::Guid {
+                value: guid.to_owned(),
+                permalink: false,
+            }),
+            ..Default::default()
+        }
     }
 
-    /// Loads our list of known items from a file.
-    pub fn load_known_items(&mut self) -> std::io::Result<()> {
-        use std::io::ErrorKind;
+    /// Runs the same sequence of operations against any storage backend.
+    fn exercise_storage(storage: &mut dyn Storage) {
+        assert!(!storage.has_channel("news").unwrap());
+        assert!(storage.store_filtered_item("news", test_item("a")).is_err());
+        storage.add_channel("news", "News", "All the news").unwrap();
+        storage.add_channel("news", "Renamed", "Ignored").unwrap();
+        assert!(storage.has_channel("news").unwrap());
+        assert_eq!(storage.feed_names().unwrap(), vec!["news".to_string()]);
 
-        tracing::debug!("Loading known items from file");
-        match std::fs::read_to_string(&self.known_items_file) {
-            // File was read, attempt to deserialize and store.
-            Ok(content) => {
-                self.known_items = serde_json::from_str(&content)?;
-                tracing::info!("Loaded {} known items from file", self.known_items.len());
+        // Items are known per feed.
+        let item = test_item("a");
+        assert!(!storage.is_known("news", &item).unwrap());
+        storage.record_as_known("news", &item).unwrap();
+        assert!(storage.is_known("news", &item).unwrap());
+        assert!(!storage.is_known("other", &item).unwrap());
 
-                Ok(())
-            }
+        // Only the newest items are kept.
+        let decision = |accepted| FilterDecision {
+            accepted,
+            response: None,
+            decided_at: chrono::Utc::now(),
+        };
+        for guid in ["a", "b", "c", "d"] {
+            storage
+                .record_decision("news", &test_item(guid), &decision(true))
+                .unwrap();
+            storage
+                .store_filtered_item("news", test_item(guid))
+                .unwrap();
+        }
+        let feed = storage.feed("news").unwrap().unwrap();
+        assert_eq!(feed.title, "News");
+        let guids: Vec<_> = feed.items.iter().map(super::item_to_guid).collect();
+        assert_eq!(guids, vec!["b", "c", "d"]);
 
-            // File did not exist: continue.
-            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+        // Rejected items are kept apart, with their own limit.
+        for guid in ["e", "f", "g"] {
+            let rejected = RejectedItem {
+                item: test_item(guid),
+                decision: decision(false),
+            };
+            storage.store_rejected_item("news", rejected).unwrap();
+        }
+        let rejected = storage.rejected_items("news").unwrap();
+        let guids: Vec<_> = rejected
+            .iter()
+            .map(|rejected| super::item_to_guid(&rejected.item))
+            .collect();
+        assert_eq!(guids, vec!["f", "g"]);
+        assert!(!rejected[0].decision.accepted);
+        assert!(storage.rejected_items("other").unwrap().is_empty());
 
-            // Other errors: fail.
-            Err(error) => Err(error),
+        // Pending items are held until removed.
+        for guid in ["h", "i"] {
+            let pending = PendingItem {
+                item: test_item(guid),
+                deferred_at: chrono::Utc::now(),
+            };
+            storage.store_pending_item("news", pending).unwrap();
         }
+        storage
+            .remove_pending_item("news", &test_item("h"))
+            .unwrap();
+        let pending = storage.pending_items("news").unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(super::item_to_guid(&pending[0].item), "i");
+        assert!(storage.pending_items("other").unwrap().is_empty());
+
+        let validators = CacheValidators {
+            etag: Some("\"abc\"".to_owned()),
+            last_modified: None,
+        };
+        storage.set_validators("news", validators).unwrap();
+        let validators = storage.validators("news").unwrap().unwrap();
+        assert_eq!(validators.etag.as_deref(), Some("\"abc\""));
+        assert!(storage.validators("other").unwrap().is_none());
+
+        storage.remove_channel("news").unwrap();
+        assert!(!storage.has_channel("news").unwrap());
+        assert!(storage.feed_names().unwrap().is_empty());
+        assert!(!storage.is_known("news", &item).unwrap());
+        assert!(storage.pending_items("news").unwrap().is_empty());
+        assert!(storage.validators("news").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut storage = MemoryStorage::new(
+            3,
+            2,
+            directory.path().join("known-items.json"),
+            directory.path().join("filtered-items.json"),
+            directory.path().join("rejected-items.json"),
+            directory.path().join("pending-items.json"),
+        );
+        exercise_storage(&mut storage);
+    }
+
+    #[test]
+    fn test_sqlite_storage() {
+        let mut storage = SqliteStorage::open_in_memory(3, 2).unwrap();
+        exercise_storage(&mut storage);
     }
 }
@@ -0,0 +1,438 @@
+//! In-memory storage, persisted to JSON files.
+
+use super::{
+    BACKUP_SUFFIX, CacheValidators, KNOWN_ITEMS_LIMIT, PendingItem, RejectedItem, Storage,
+    StoredFeed, item_to_guid, read_json_file, with_suffix, write_atomically,
+};
+use crate::filter::FilterDecision;
+use anyhow::bail;
+use rss::Item;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+
+/// The contents of the known items file.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct KnownItemsFile<'a> {
+    known_items: Cow<'a, HashMap<String, VecDeque<String>>>,
+    #[serde(default)]
+    validators: Cow<'a, HashMap<String, CacheValidators>>,
+}
+
+/// Known items files written by older versions only held the list of items.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KnownItemsFormat {
+    Current(KnownItemsFile<'static>),
+    Legacy(HashMap<String, VecDeque<String>>),
+}
+
+pub struct MemoryStorage {
+    /// A list of items we're serving to the user.
+    feeds: HashMap<String, StoredFeed>,
+
+    /// How many items we can keep in each feed.
+    max_items: usize,
+
+    /// Items the filter rejected, kept for review.
+    rejected_items: HashMap<String, VecDeque<RejectedItem>>,
+
+    /// How many rejected items we can keep for each feed.
+    max_rejected_items: usize,
+
+    /// Items the filter couldn't decide on, waiting to be tried again.
+    pending_items: HashMap<String, VecDeque<PendingItem>>,
+
+    /// A list of items we've seen before (and might have filtered out).
+    ///
+    /// Note: not limited by `max_items`.
+    known_items: HashMap<String, VecDeque<String>>,
+
+    /// The cache validators last returned by each feed's server.
+    validators: HashMap<String, CacheValidators>,
+
+    /// A location to store and load known items (and cache validators).
+    known_items_file: PathBuf,
+
+    /// A location to store and load the items we're serving.
+    filtered_items_file: PathBuf,
+
+    /// A location to store and load the items the filter rejected.
+    rejected_items_file: PathBuf,
+
+    /// A location to store and load the items waiting for a decision.
+    pending_items_file: PathBuf,
+}
+
+impl MemoryStorage {
+    pub fn new(
+        max_items: usize,
+        max_rejected_items: usize,
+        known_items_file: PathBuf,
+        filtered_items_file: PathBuf,
+        rejected_items_file: PathBuf,
+        pending_items_file: PathBuf,
+    ) -> Self {
+        Self {
+            feeds: HashMap::new(),
+            max_items,
+            rejected_items: HashMap::new(),
+            max_rejected_items,
+            pending_items: HashMap::new(),
+            known_items: HashMap::new(),
+            validators: HashMap::new(),
+            known_items_file,
+            filtered_items_file,
+            rejected_items_file,
+            pending_items_file,
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn add_channel(
+        &mut self,
+        feed_name: &str,
+        title: &str,
+        description: &str,
+    ) -> anyhow::Result<()> {
+        if !self.feeds.contains_key(feed_name) {
+            self.feeds.insert(
+                feed_name.to_owned(),
+                StoredFeed {
+                    title: title.to_owned(),
+                    description: description.to_owned(),
+                    items: VecDeque::new(),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    fn has_channel(&self, feed_name: &str) -> anyhow::Result<bool> {
+        Ok(self.feeds.contains_key(feed_name))
+    }
+
+    fn remove_channel(&mut self, feed_name: &str) -> anyhow::Result<()> {
+        self.feeds.remove(feed_name);
+        self.rejected_items.remove(feed_name);
+        self.pending_items.remove(feed_name);
+        self.known_items.remove(feed_name);
+        self.validators.remove(feed_name);
+        Ok(())
+    }
+
+    fn feed(&self, feed_name: &str) -> anyhow::Result<Option<StoredFeed>> {
+        Ok(self.feeds.get(feed_name).cloned())
+    }
+
+    fn feed_names(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.feeds.keys().cloned().collect())
+    }
+
+    fn store_filtered_item(&mut self, feed_name: &str, item: Item) -> anyhow::Result<()> {
+        let Some(feed) = self.feeds.get_mut(feed_name) else {
+            bail!("Tried to store an item in unknown feed {feed_name}");
+        };
+
+        feed.items.push_back(item);
+
+        // Remove oldest items if we exceed the limit.
+        while feed.items.len() > self.max_items {
+            feed.items.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn store_rejected_item(
+        &mut self,
+        feed_name: &str,
+        rejected: RejectedItem,
+    ) -> anyhow::Result<()> {
+        let rejected_items = self.rejected_items.entry(feed_name.to_owned()).or_default();
+
+        rejected_items.push_back(rejected);
+        while rejected_items.len() > self.max_rejected_items {
+            rejected_items.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn rejected_items(&self, feed_name: &str) -> anyhow::Result<Vec<RejectedItem>> {
+        Ok(self
+            .rejected_items
+            .get(feed_name)
+            .map(|rejected_items| rejected_items.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn store_pending_item(&mut self, feed_name: &str, pending: PendingItem) -> anyhow::Result<()> {
+        self.pending_items
+            .entry(feed_name.to_owned())
+            .or_default()
+            .push_back(pending);
+        Ok(())
+    }
+
+    fn pending_items(&self, feed_name: &str) -> anyhow::Result<Vec<PendingItem>> {
+        Ok(self
+            .pending_items
+            .get(feed_name)
+            .map(|pending_items| pending_items.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn remove_pending_item(&mut self, feed_name: &str, item: &Item) -> anyhow::Result<()> {
+        let guid = item_to_guid(item);
+        if let Some(pending_items) = self.pending_items.get_mut(feed_name) {
+            pending_items.retain(|pending| item_to_guid(&pending.item) != guid);
+        }
+        Ok(())
+    }
+
+    /// Filter decisions aren't kept in memory, beyond those of rejected items.
+    fn record_decision(&mut self, _: &str, _: &Item, _: &FilterDecision) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn is_known(&self, feed_name: &str, item: &Item) -> anyhow::Result<bool> {
+        let item_guid = item_to_guid(item);
+
+        Ok(match self.known_items.get(feed_name) {
+            Some(known_feed_items) => known_feed_items.contains(&item_guid),
+            None => false,
+        })
+    }
+
+    fn record_as_known(&mut self, feed_name: &str, item: &Item) -> anyhow::Result<()> {
+        let item_guid = item_to_guid(item);
+        let item_guids = self.known_items.entry(feed_name.to_owned()).or_default();
+
+        item_guids.push_back(item_guid);
+        while item_guids.len() > KNOWN_ITEMS_LIMIT {
+            item_guids.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn validators(&self, feed_name: &str) -> anyhow::Result<Option<CacheValidators>> {
+        Ok(self.validators.get(feed_name).cloned())
+    }
+
+    fn set_validators(
+        &mut self,
+        feed_name: &str,
+        validators: CacheValidators,
+    ) -> anyhow::Result<()> {
+        self.validators.insert(feed_name.to_owned(), validators);
+        Ok(())
+    }
+
+    /// Save our list of known items to a file, along with our cache validators.
+    ///
+    /// Replaces the file atomically, keeping its previous contents as a backup.
+    fn save_known_items(&self) -> anyhow::Result<()> {
+        tracing::debug!("Saving known items to file");
+        let json = serde_json::to_string(&KnownItemsFile {
+            known_items: Cow::Borrowed(&self.known_items),
+            validators: Cow::Borrowed(&self.validators),
+        })?;
+        write_atomically(&self.known_items_file, json.as_bytes(), true)?;
+        Ok(())
+    }
+
+    /// Loads our list of known items from a file.
+    ///
+    /// Falls back to the backup if the file can't be read, and starts afresh if neither can.
+    fn load_known_items(&mut self) -> anyhow::Result<()> {
+        tracing::debug!("Loading known items from file");
+
+        // No file, or none we could read: continue.
+        let Some(file) =
+            read_json_file_or_backup::<KnownItemsFormat>(&self.known_items_file, "known items")
+        else {
+            return Ok(());
+        };
+
+        match file {
+            KnownItemsFormat::Current(file) => {
+                self.known_items = file.known_items.into_owned();
+                self.validators = file.validators.into_owned();
+            }
+            KnownItemsFormat::Legacy(known_items) => self.known_items = known_items,
+        }
+        tracing::info!("Loaded {} known items from file", self.known_items.len());
+
+        Ok(())
+    }
+
+    /// Save the items we're serving, and those rejected or pending, to their files.
+    ///
+    /// Replaces the files atomically, keeping their previous contents as backups.
+    fn save_filtered_items(&self) -> anyhow::Result<()> {
+        tracing::debug!("Saving filtered items to file");
+        let json = serde_json::to_string(&self.feeds)?;
+        write_atomically(&self.filtered_items_file, json.as_bytes(), true)?;
+
+        tracing::debug!("Saving rejected items to file");
+        let json = serde_json::to_string(&self.rejected_items)?;
+        write_atomically(&self.rejected_items_file, json.as_bytes(), true)?;
+
+        tracing::debug!("Saving pending items to file");
+        let json = serde_json::to_string(&self.pending_items)?;
+        write_atomically(&self.pending_items_file, json.as_bytes(), true)?;
+        Ok(())
+    }
+
+    /// Loads the items we're serving, and those rejected or pending, from their files.
+    ///
+    /// Falls back to the backups if the files can't be read, and starts afresh if neither can.
+    fn load_filtered_items(&mut self) -> anyhow::Result<()> {
+        tracing::debug!("Loading filtered items from file");
+        if let Some(feeds) = read_json_file_or_backup::<HashMap<String, StoredFeed>>(
+            &self.filtered_items_file,
+            "filtered items",
+        ) {
+            self.feeds = feeds;
+
+            // The item limit may have been lowered since the file was written.
+            for feed in self.feeds.values_mut() {
+                while feed.items.len() > self.max_items {
+                    feed.items.pop_front();
+                }
+            }
+
+            let item_count: usize = self.feeds.values().map(|feed| feed.items.len()).sum();
+            tracing::info!(
+                "Loaded {item_count} filtered items in {} feeds from file",
+                self.feeds.len()
+            );
+        }
+
+        tracing::debug!("Loading rejected items from file");
+        if let Some(rejected_items) = read_json_file_or_backup::<
+            HashMap<String, VecDeque<RejectedItem>>,
+        >(&self.rejected_items_file, "rejected items")
+        {
+            self.rejected_items = rejected_items;
+
+            for rejected_items in self.rejected_items.values_mut() {
+                while rejected_items.len() > self.max_rejected_items {
+                    rejected_items.pop_front();
+                }
+            }
+
+            let item_count: usize = self.rejected_items.values().map(VecDeque::len).sum();
+            tracing::info!("Loaded {item_count} rejected items from file");
+        }
+
+        tracing::debug!("Loading pending items from file");
+        if let Some(pending_items) = read_json_file_or_backup::<
+            HashMap<String, VecDeque<PendingItem>>,
+        >(&self.pending_items_file, "pending items")
+        {
+            self.pending_items = pending_items;
+
+            let item_count: usize = self.pending_items.values().map(VecDeque::len).sum();
+            tracing::info!("Loaded {item_count} pending items from file");
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads and parses a JSON file, falling back to its backup if it can't be read.
+///
+/// Returns `None` if neither exists or can be read, as losing what we held beats not starting.
+fn read_json_file_or_backup<T: DeserializeOwned>(path: &Path, what: &str) -> Option<T> {
+    let error = match read_json_file(path) {
+        Ok(contents) => return contents,
+        Err(error) => error,
+    };
+
+    tracing::warn!("{error:#}, trying the backup");
+    match read_json_file(&with_suffix(path, BACKUP_SUFFIX)) {
+        Ok(Some(contents)) => {
+            tracing::info!("Recovered {what} from the backup");
+            Some(contents)
+        }
+        Ok(None) => {
+            tracing::warn!("No backup of {what} found, starting afresh");
+            None
+        }
+        Err(error) => {
+            tracing::warn!("{error:#}, starting afresh");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KnownItemsFormat;
+    use crate::storage::{MemoryStorage, Storage};
+
+    #[test]
+    fn test_load_known_items_formats() {
+        let legacy = r#"{"hackernews": ["a", "b"]}"#;
+        let KnownItemsFormat::Legacy(known_items) = serde_json::from_str(legacy).unwrap() else {
+            panic!("Legacy file parsed as the current format");
+        };
+        assert_eq!(known_items["hackernews"].len(), 2);
+
+        let current = r#"{"known_items": {"hackernews": ["a"]}, "validators": {"hackernews": {"etag": "\"abc\"", "last_modified": null}}}"#;
+        let KnownItemsFormat::Current(file) = serde_json::from_str(current).unwrap() else {
+            panic!("Current file parsed as the legacy format");
+        };
+        assert_eq!(file.known_items["hackernews"].len(), 1);
+        assert_eq!(
+            file.validators["hackernews"].etag.as_deref(),
+            Some("\"abc\"")
+        );
+    }
+
+    #[test]
+    fn test_load_known_items_from_backup() {
+        let directory = tempfile::tempdir().unwrap();
+        let storage = || {
+            MemoryStorage::new(
+                3,
+                2,
+                directory.path().join("known-items.json"),
+                directory.path().join("filtered-items.json"),
+                directory.path().join("rejected-items.json"),
+                directory.path().join("pending-items.json"),
+            )
+        };
+        let item = rss::Item {
+            link: Some("https://example.com/a".to_owned()),
+            ..Default::default()
+        };
+
+        // Save twice so the backup holds the item, then truncate the file.
+        let mut saved = storage();
+        saved.record_as_known("news", &item).unwrap();
+        saved.save_known_items().unwrap();
+        saved.save_known_items().unwrap();
+        std::fs::write(
+            directory.path().join("known-items.json"),
+            r#"{"known_items": {"ne"#,
+        )
+        .unwrap();
+
+        let mut loaded = storage();
+        loaded.load_known_items().unwrap();
+        assert!(loaded.is_known("news", &item).unwrap());
+
+        // Without a backup, a corrupt file still doesn't stop us from starting.
+        std::fs::write(directory.path().join("filtered-items.json"), "{").unwrap();
+        loaded.load_filtered_items().unwrap();
+        assert!(loaded.feed_names().unwrap().is_empty());
+    }
+}
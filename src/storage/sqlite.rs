@@ -0,0 +1,446 @@
+//! SQLite storage.
+
+use super::{
+    CacheValidators, KNOWN_ITEMS_LIMIT, PendingItem, RejectedItem, Storage, StoredFeed,
+    item_to_guid,
+};
+use crate::filter::FilterDecision;
+use anyhow::{Context, bail};
+use rss::Item;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+
+/// Schema migrations, applied in order.
+///
+/// The database's `user_version` records how many have been applied. Never edit a migration
+/// once released, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema.
+    "CREATE TABLE feeds (
+        name TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        description TEXT NOT NULL,
+        etag TEXT,
+        last_modified TEXT
+    );
+    CREATE TABLE items (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        feed_name TEXT NOT NULL REFERENCES feeds (name),
+        guid TEXT NOT NULL,
+        item TEXT NOT NULL,
+        stored_at TEXT NOT NULL
+    );
+    CREATE INDEX items_by_feed ON items (feed_name, id);
+    CREATE TABLE known_items (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        feed_name TEXT NOT NULL,
+        guid TEXT NOT NULL,
+        UNIQUE (feed_name, guid)
+    );
+    CREATE TABLE decisions (
+        feed_name TEXT NOT NULL,
+        guid TEXT NOT NULL,
+        accepted INTEGER NOT NULL,
+        decided_at TEXT NOT NULL,
+        PRIMARY KEY (feed_name, guid)
+    );",
+    // 2: Items rejected by the filter.
+    "CREATE TABLE rejected_items (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        feed_name TEXT NOT NULL,
+        guid TEXT NOT NULL,
+        item TEXT NOT NULL,
+        decision TEXT NOT NULL
+    );
+    CREATE INDEX rejected_items_by_feed ON rejected_items (feed_name, id);",
+    // 3: The LLM's reasoning behind decisions.
+    "ALTER TABLE decisions ADD COLUMN reason TEXT;
+    ALTER TABLE decisions ADD COLUMN topics TEXT;",
+    // 4: Items waiting for the LLM to come back.
+    "CREATE TABLE pending_items (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        feed_name TEXT NOT NULL,
+        guid TEXT NOT NULL,
+        item TEXT NOT NULL,
+        deferred_at TEXT NOT NULL
+    );
+    CREATE INDEX pending_items_by_feed ON pending_items (feed_name, id);",
+];
+
+pub struct SqliteStorage {
+    connection: Connection,
+
+    /// How many items we can keep in each feed.
+    max_items: usize,
+
+    /// How many rejected items we can keep for each feed.
+    max_rejected_items: usize,
+}
+
+impl SqliteStorage {
+    /// Opens (or creates) a database file, bringing its schema up to date.
+    pub fn open(path: &Path, max_items: usize, max_rejected_items: usize) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open database {}", path.display()))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+
+        Self::with_connection(connection, max_items, max_rejected_items)
+    }
+
+    /// Opens a throwaway database that lives in memory.
+    #[cfg(test)]
+    pub fn open_in_memory(max_items: usize, max_rejected_items: usize) -> anyhow::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?, max_items, max_rejected_items)
+    }
+
+    fn with_connection(
+        mut connection: Connection,
+        max_items: usize,
+        max_rejected_items: usize,
+    ) -> anyhow::Result<Self> {
+        migrate(&mut connection).context("Failed to migrate database schema")?;
+
+        Ok(Self {
+            connection,
+            max_items,
+            max_rejected_items,
+        })
+    }
+}
+
+/// Applies any migrations the database hasn't seen yet.
+fn migrate(connection: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tracing::info!("Migrating database schema to version {}", index + 1);
+
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn add_channel(
+        &mut self,
+        feed_name: &str,
+        title: &str,
+        description: &str,
+    ) -> anyhow::Result<()> {
+        self.connection.execute(
+            "INSERT OR IGNORE INTO feeds (name, title, description) VALUES (?1, ?2, ?3)",
+            params![feed_name, title, description],
+        )?;
+        Ok(())
+    }
+
+    fn has_channel(&self, feed_name: &str) -> anyhow::Result<bool> {
+        let exists = self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM feeds WHERE name = ?1)",
+            params![feed_name],
+            |row| row.get(0),
+        )?;
+        Ok(exists)
+    }
+
+    fn remove_channel(&mut self, feed_name: &str) -> anyhow::Result<()> {
+        let transaction = self.connection.transaction()?;
+        for table in [
+            "items",
+            "rejected_items",
+            "pending_items",
+            "known_items",
+            "decisions",
+        ] {
+            transaction.execute(
+                &format!("DELETE FROM {table} WHERE feed_name = ?1"),
+                params![feed_name],
+            )?;
+        }
+        transaction.execute("DELETE FROM feeds WHERE name = ?1", params![feed_name])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn feed(&self, feed_name: &str) -> anyhow::Result<Option<StoredFeed>> {
+        let connection = &self.connection;
+
+        let Some((title, description)) = connection
+            .query_row(
+                "SELECT title, description FROM feeds WHERE name = ?1",
+                params![feed_name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let mut statement =
+            connection.prepare("SELECT item FROM items WHERE feed_name = ?1 ORDER BY id")?;
+        let items = statement
+            .query_map(params![feed_name], |row| row.get::<_, String>(0))?
+            .map(|item| Ok(serde_json::from_str(&item?)?))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Some(StoredFeed {
+            title,
+            description,
+            items,
+        }))
+    }
+
+    fn feed_names(&self) -> anyhow::Result<Vec<String>> {
+        let connection = &self.connection;
+        let mut statement = connection.prepare("SELECT name FROM feeds ORDER BY name")?;
+        let names = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(names)
+    }
+
+    fn store_filtered_item(&mut self, feed_name: &str, item: Item) -> anyhow::Result<()> {
+        // Items without a channel would never be served.
+        if !self.has_channel(feed_name)? {
+            bail!("Tried to store an item in unknown feed {feed_name}");
+        }
+
+        let connection = &mut self.connection;
+        let transaction = connection.transaction()?;
+
+        transaction.execute(
+            "INSERT INTO items (feed_name, guid, item, stored_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                feed_name,
+                item_to_guid(&item),
+                serde_json::to_string(&item)?,
+                chrono::Utc::now().to_rfc3339(),
+            ],
+        )?;
+
+        // Remove oldest items if we exceed the limit.
+        transaction.execute(
+            "DELETE FROM items WHERE feed_name = ?1 AND id NOT IN
+                (SELECT id FROM items WHERE feed_name = ?1 ORDER BY id DESC LIMIT ?2)",
+            params![feed_name, self.max_items],
+        )?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn store_rejected_item(
+        &mut self,
+        feed_name: &str,
+        rejected: RejectedItem,
+    ) -> anyhow::Result<()> {
+        let connection = &mut self.connection;
+        let transaction = connection.transaction()?;
+
+        transaction.execute(
+            "INSERT INTO rejected_items (feed_name, guid, item, decision) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                feed_name,
+                item_to_guid(&rejected.item),
+                serde_json::to_string(&rejected.item)?,
+                serde_json::to_string(&rejected.decision)?,
+            ],
+        )?;
+        transaction.execute(
+            "DELETE FROM rejected_items WHERE feed_name = ?1 AND id NOT IN
+                (SELECT id FROM rejected_items WHERE feed_name = ?1 ORDER BY id DESC LIMIT ?2)",
+            params![feed_name, self.max_rejected_items],
+        )?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn rejected_items(&self, feed_name: &str) -> anyhow::Result<Vec<RejectedItem>> {
+        let connection = &self.connection;
+        let mut statement = connection.prepare(
+            "SELECT item, decision FROM rejected_items WHERE feed_name = ?1 ORDER BY id",
+        )?;
+        let rejected_items = statement
+            .query_map(params![feed_name], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .map(|row| {
+                let (item, decision) = row?;
+                Ok(RejectedItem {
+                    item: serde_json::from_str(&item)?,
+                    decision: serde_json::from_str(&decision)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(rejected_items)
+    }
+
+    fn store_pending_item(&mut self, feed_name: &str, pending: PendingItem) -> anyhow::Result<()> {
+        self.connection.execute(
+            "INSERT INTO pending_items (feed_name, guid, item, deferred_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                feed_name,
+                item_to_guid(&pending.item),
+                serde_json::to_string(&pending.item)?,
+                pending.deferred_at.to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn pending_items(&self, feed_name: &str) -> anyhow::Result<Vec<PendingItem>> {
+        let connection = &self.connection;
+        let mut statement = connection.prepare(
+            "SELECT item, deferred_at FROM pending_items WHERE feed_name = ?1 ORDER BY id",
+        )?;
+        let pending_items = statement
+            .query_map(params![feed_name], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .map(|row| {
+                let (item, deferred_at) = row?;
+                Ok(PendingItem {
+                    item: serde_json::from_str(&item)?,
+                    deferred_at: chrono::DateTime::parse_from_rfc3339(&deferred_at)?.to_utc(),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(pending_items)
+    }
+
+    fn remove_pending_item(&mut self, feed_name: &str, item: &Item) -> anyhow::Result<()> {
+        self.connection.execute(
+            "DELETE FROM pending_items WHERE feed_name = ?1 AND guid = ?2",
+            params![feed_name, item_to_guid(item)],
+        )?;
+        Ok(())
+    }
+
+    fn record_decision(
+        &mut self,
+        feed_name: &str,
+        item: &Item,
+        decision: &FilterDecision,
+    ) -> anyhow::Result<()> {
+        let response = decision.response.as_ref();
+        let topics = match response {
+            Some(response) => Some(serde_json::to_string(&response.topics)?),
+            None => None,
+        };
+
+        self.connection.execute(
+            "INSERT OR REPLACE INTO decisions (feed_name, guid, accepted, decided_at, reason, topics)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                feed_name,
+                item_to_guid(item),
+                decision.accepted,
+                decision.decided_at.to_rfc3339(),
+                response.and_then(|response| response.reason.as_deref()),
+                topics,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn is_known(&self, feed_name: &str, item: &Item) -> anyhow::Result<bool> {
+        let known = self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM known_items WHERE feed_name = ?1 AND guid = ?2)",
+            params![feed_name, item_to_guid(item)],
+            |row| row.get(0),
+        )?;
+        Ok(known)
+    }
+
+    fn record_as_known(&mut self, feed_name: &str, item: &Item) -> anyhow::Result<()> {
+        let connection = &mut self.connection;
+        let transaction = connection.transaction()?;
+
+        transaction.execute(
+            "INSERT OR IGNORE INTO known_items (feed_name, guid) VALUES (?1, ?2)",
+            params![feed_name, item_to_guid(item)],
+        )?;
+        transaction.execute(
+            "DELETE FROM known_items WHERE feed_name = ?1 AND id NOT IN
+                (SELECT id FROM known_items WHERE feed_name = ?1 ORDER BY id DESC LIMIT ?2)",
+            params![feed_name, KNOWN_ITEMS_LIMIT],
+        )?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn validators(&self, feed_name: &str) -> anyhow::Result<Option<CacheValidators>> {
+        let validators = self
+            .connection
+            .query_row(
+                "SELECT etag, last_modified FROM feeds WHERE name = ?1",
+                params![feed_name],
+                |row| {
+                    Ok(CacheValidators {
+                        etag: row.get(0)?,
+                        last_modified: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(validators)
+    }
+
+    fn set_validators(
+        &mut self,
+        feed_name: &str,
+        validators: CacheValidators,
+    ) -> anyhow::Result<()> {
+        self.connection.execute(
+            "UPDATE feeds SET etag = ?2, last_modified = ?3 WHERE name = ?1",
+            params![feed_name, validators.etag, validators.last_modified],
+        )?;
+        Ok(())
+    }
+
+    /// Writes go straight to the database.
+    fn save_known_items(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Reads go straight to the database.
+    fn load_known_items(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Writes go straight to the database.
+    fn save_filtered_items(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Reads go straight to the database, but trims feeds in case the item limits were lowered.
+    fn load_filtered_items(&mut self) -> anyhow::Result<()> {
+        let connection = &self.connection;
+        connection.execute(
+            "DELETE FROM items WHERE id NOT IN (SELECT id FROM
+                (SELECT id, ROW_NUMBER() OVER (PARTITION BY feed_name ORDER BY id DESC) AS rank
+                    FROM items)
+                WHERE rank <= ?1)",
+            params![self.max_items],
+        )?;
+        connection.execute(
+            "DELETE FROM rejected_items WHERE id NOT IN (SELECT id FROM
+                (SELECT id, ROW_NUMBER() OVER (PARTITION BY feed_name ORDER BY id DESC) AS rank
+                    FROM rejected_items)
+                WHERE rank <= ?1)",
+            params![self.max_rejected_items],
+        )?;
+
+        let item_count: usize =
+            connection.query_row("SELECT COUNT(*) FROM items", [], |row| row.get(0))?;
+        tracing::info!("Serving {item_count} filtered items from the database");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+//! Token and cost accounting for the LLM filter, with daily and monthly budgets.
+
+use crate::storage::{read_json_file, write_atomically};
+use chrono::{Datelike, NaiveDate, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Forget usage older than this many days.
+const RETENTION_DAYS: i64 = 400;
+
+/// What was spent on LLM requests.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, other: &UsageTotals) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost += other.cost;
+    }
+}
+
+/// What was spent on one feed, with one model, in one (UTC) day.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UsageRecord {
+    pub date: NaiveDate,
+    pub feed: String,
+    pub model: String,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+/// Usage so far, as served at `/usage`.
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub today: UsageTotals,
+    pub this_month: UsageTotals,
+    pub daily_budget: Option<f64>,
+    pub monthly_budget: Option<f64>,
+    pub records: Vec<UsageRecord>,
+}
+
+struct Inner {
+    records: HashMap<(NaiveDate, String, String), UsageTotals>,
+
+    /// The most we can spend in a day, and in a month.
+    daily_budget: Option<f64>,
+    monthly_budget: Option<f64>,
+
+    /// A location to store and load usage.
+    file: PathBuf,
+}
+
+/// Shared record of what the LLM filter spends.
+#[derive(Clone)]
+pub struct UsageTracker {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl UsageTracker {
+    pub fn new(file: PathBuf, daily_budget: Option<f64>, monthly_budget: Option<f64>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                records: HashMap::new(),
+                daily_budget,
+                monthly_budget,
+                file,
+            })),
+        }
+    }
+
+    /// Records a request made to the LLM on behalf of a feed.
+    pub fn record(&self, feed_name: &str, model: &str, usage: UsageTotals) {
+        let date = Utc::now().date_naive();
+        let mut inner = self.lock();
+        inner
+            .records
+            .entry((date, feed_name.to_owned(), model.to_owned()))
+            .or_default()
+            .add(&usage);
+    }
+
+    /// Returns which budget has been used up, if any.
+    pub fn exceeded_budget(&self) -> Option<&'static str> {
+        let inner = self.lock();
+        let (today, this_month) = inner.current_totals();
+
+        if inner
+            .daily_budget
+            .is_some_and(|budget| today.cost >= budget)
+        {
+            Some("daily")
+        } else if inner
+            .monthly_budget
+            .is_some_and(|budget| this_month.cost >= budget)
+        {
+            Some("monthly")
+        } else {
+            None
+        }
+    }
+
+    /// Returns usage so far, newest first.
+    pub fn report(&self) -> UsageReport {
+        let inner = self.lock();
+        let (today, this_month) = inner.current_totals();
+
+        let mut records = inner.to_records();
+        records.sort_by(|a, b| (b.date, &a.feed, &a.model).cmp(&(a.date, &b.feed, &b.model)));
+
+        UsageReport {
+            today,
+            this_month,
+            daily_budget: inner.daily_budget,
+            monthly_budget: inner.monthly_budget,
+            records,
+        }
+    }
+
+    /// Writes usage to a file.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let mut inner = self.lock();
+        inner.prune();
+
+        tracing::debug!("Saving LLM usage to file");
+        let json = serde_json::to_string(&inner.to_records())?;
+        write_atomically(&inner.file, json.as_bytes(), false)?;
+        Ok(())
+    }
+
+    /// Loads usage from a file.
+    pub fn load(&self) -> anyhow::Result<()> {
+        let mut inner = self.lock();
+
+        tracing::debug!("Loading LLM usage from file");
+        if let Some(records) = read_json_file::<Vec<UsageRecord>>(&inner.file)? {
+            inner.records = records
+                .into_iter()
+                .map(|record| ((record.date, record.feed, record.model), record.totals))
+                .collect();
+            inner.prune();
+            tracing::info!("Loaded {} LLM usage records from file", inner.records.len());
+        }
+
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Inner {
+    /// Adds up usage for the current (UTC) day and month.
+    fn current_totals(&self) -> (UsageTotals, UsageTotals) {
+        let today = Utc::now().date_naive();
+        let mut day_totals = UsageTotals::default();
+        let mut month_totals = UsageTotals::default();
+
+        for ((date, _, _), totals) in &self.records {
+            if (date.year(), date.month()) == (today.year(), today.month()) {
+                month_totals.add(totals);
+                if *date == today {
+                    day_totals.add(totals);
+                }
+            }
+        }
+
+        (day_totals, month_totals)
+    }
+
+    fn to_records(&self) -> Vec<UsageRecord> {
+        self.records
+            .iter()
+            .map(|((date, feed, model), totals)| UsageRecord {
+                date: *date,
+                feed: feed.clone(),
+                model: model.clone(),
+                totals: *totals,
+            })
+            .collect()
+    }
+
+    /// Drops usage we no longer need to keep.
+    fn prune(&mut self) {
+        let oldest = Utc::now().date_naive() - TimeDelta::days(RETENTION_DAYS);
+        self.records.retain(|(date, _, _), _| *date >= oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UsageTotals, UsageTracker};
+
+    #[test]
+    fn test_budgets() {
+        let usage = |cost| UsageTotals {
+            requests: 1,
+            prompt_tokens: 100,
+            completion_tokens: 10,
+            cost,
+        };
+
+        let directory = tempfile::tempdir().unwrap();
+        let tracker = UsageTracker::new(directory.path().join("usage.json"), Some(1.0), Some(10.0));
+        tracker.record("news", "openai/gpt-5", usage(0.5));
+        tracker.record("news", "openai/gpt-5", usage(0.25));
+        tracker.record("blog", "ollama/llama3.1", usage(0.0));
+        assert_eq!(tracker.exceeded_budget(), None);
+
+        let report = tracker.report();
+        assert_eq!(report.today.requests, 3);
+        assert_eq!(report.today.prompt_tokens, 300);
+        assert_eq!(report.records.len(), 2);
+
+        tracker.record("blog", "openai/gpt-5", usage(0.25));
+        assert_eq!(tracker.exceeded_budget(), Some("daily"));
+    }
+}